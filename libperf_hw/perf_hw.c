@@ -22,18 +22,37 @@
 typedef struct _counter_t* counter_t;
 struct _counter_t {
   int nbcores;
+  /* ids of the cpus the counters are opened on, counters[i][core] is opened on cpu cpus[core] */
+  int *cpus;
   int nbperf;
   int **counters;
-  int **perf_indexes;
+  struct counter_option *events;
+  /* events of a group are contiguous, group_sizes[i] is the number of events of the group led by event i, 0 if event i is not a leader */
+  int *group_sizes;
+  /* errors[i] is the errno of perf_event_open if event i could not be opened on any core, 0 otherwise */
+  int *errors;
+  /* time_enabled and time_running of each counter at the last read, PERF_EVENT_IOC_RESET does not reset them
+  events of a group share the times of their leader */
+  unsigned long long **time_enabled;
+  unsigned long long **time_running;
+};
+
+/* layout of a read on a counter opened with PERF_FORMAT_TOTAL_TIME_ENABLED|PERF_FORMAT_TOTAL_TIME_RUNNING */
+struct read_format {
+  unsigned long long value;
+  unsigned long long time_enabled;
+  unsigned long long time_running;
 };
 
 /* represent a perf_event cgroup
 cgroup_name : name of the cgroup
+metrics : list of metrics the counters were opened for
 g_counter : perf_event counter associated to the cgroup
 next : reference to the next cgroup in the linked list of cgroups */
 typedef struct _cgroup_t *cgroup_t;
 struct _cgroup_t {
    char *cgroup_name;
+   char *metrics;
    counter_t g_counter;
    struct _cgroup_t *next;
 };
@@ -43,16 +62,22 @@ cgroup_t head = NULL;
 cgroup_t current = NULL;
 
 cgroup_t find(char *cgroup_name);
-void insertFirst(char *cgroup_name, counter_t g_counter);
+void insertFirst(char *cgroup_name, char *metrics, counter_t g_counter);
 void remove_cgroup(char *cgroup_name);
 
 // name : metric name in natural language
 // perf_type : type of event associated with the metric (field type of perf_event_attr struct)
 // perf_key : metric name (field config of perf_event_attr struct)
+// perf_config1, perf_config2 : extension of perf_key used by some pmu events (fields config1 and config2 of perf_event_attr struct)
+// exclude_user, exclude_kernel : count only in kernel mode (modifier :k) or in user mode (modifier :u)
 typedef struct counter_option {
   char *name;
   __u32 perf_type;
   __u64 perf_key;
+  __u64 perf_config1;
+  __u64 perf_config2;
+  bool exclude_user;
+  bool exclude_kernel;
 } counter_option;
 
 // metrics that can be collected using perf_event_open
@@ -66,6 +91,7 @@ static counter_option perf_static_info[] = {
 { .name = "branch_misses", .perf_type = PERF_TYPE_HARDWARE, .perf_key = PERF_COUNT_HW_BRANCH_MISSES},
 { .name = "bus_cycles", .perf_type = PERF_TYPE_HARDWARE, .perf_key = PERF_COUNT_HW_BUS_CYCLES},
 { .name = "ref_cpu_cycles", .perf_type = PERF_TYPE_HARDWARE, .perf_key = PERF_COUNT_HW_REF_CPU_CYCLES},
+// first names of the generalized cache events, kept with their ids in metrics_order.yml, config 0 for operation read and result access
 { .name = "cache_l1d", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1D},
 { .name = "cache_ll", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_LL},
 { .name = "cache_dtlb", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_DTLB},
@@ -75,6 +101,49 @@ static counter_option perf_static_info[] = {
 { .name = "cache_op_read", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_OP_READ},
 { .name = "cache_op_prefetch", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_OP_PREFETCH},
 { .name = "cache_result_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_RESULT_ACCESS},
+// generalized cache events, config is cache id | (operation << 8) | (result << 16)
+{ .name = "cache_l1d_read_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1D | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_l1d_read_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1D | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_l1d_write_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1D | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_l1d_write_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1D | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_l1d_prefetch_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1D | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_l1d_prefetch_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1D | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_l1i_read_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1I | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_l1i_read_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1I | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_l1i_write_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1I | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_l1i_write_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1I | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_l1i_prefetch_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1I | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_l1i_prefetch_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_L1I | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_ll_read_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_LL | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_ll_read_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_LL | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_ll_write_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_LL | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_ll_write_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_LL | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_ll_prefetch_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_LL | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_ll_prefetch_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_LL | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_dtlb_read_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_DTLB | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_dtlb_read_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_DTLB | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_dtlb_write_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_DTLB | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_dtlb_write_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_DTLB | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_dtlb_prefetch_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_DTLB | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_dtlb_prefetch_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_DTLB | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_itlb_read_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_ITLB | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_itlb_read_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_ITLB | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_itlb_write_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_ITLB | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_itlb_write_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_ITLB | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_itlb_prefetch_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_ITLB | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_itlb_prefetch_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_ITLB | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_bpu_read_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_BPU | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_bpu_read_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_BPU | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_bpu_write_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_BPU | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_bpu_write_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_BPU | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_bpu_prefetch_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_BPU | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_bpu_prefetch_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_BPU | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_node_read_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_NODE | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_node_read_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_NODE | (PERF_COUNT_HW_CACHE_OP_READ << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_node_write_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_NODE | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_node_write_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_NODE | (PERF_COUNT_HW_CACHE_OP_WRITE << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
+{ .name = "cache_node_prefetch_access", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_NODE | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_ACCESS << 16)},
+{ .name = "cache_node_prefetch_miss", .perf_type = PERF_TYPE_HW_CACHE, .perf_key = PERF_COUNT_HW_CACHE_NODE | (PERF_COUNT_HW_CACHE_OP_PREFETCH << 8) | (PERF_COUNT_HW_CACHE_RESULT_MISS << 16)},
 { .name = "cpu_clock", .perf_type = PERF_TYPE_SOFTWARE, .perf_key = PERF_COUNT_SW_CPU_CLOCK},
 { .name = "task_clock", .perf_type = PERF_TYPE_SOFTWARE, .perf_key = PERF_COUNT_SW_TASK_CLOCK},
 { .name = "page_faults", .perf_type = PERF_TYPE_SOFTWARE, .perf_key = PERF_COUNT_SW_PAGE_FAULTS},
@@ -88,33 +157,83 @@ static counter_option perf_static_info[] = {
 { .name = "bpf_output", .perf_type = PERF_TYPE_SOFTWARE, .perf_key = PERF_COUNT_SW_BPF_OUTPUT},
 };
 
-static unsigned int nb_counter_option = 28;
-
-int nb_perf = 5;
-int* perf_indexes=NULL;
+static unsigned int nb_counter_option = sizeof(perf_static_info) / sizeof(counter_option);
+
+// fill event with the event described by token, either a name of perf_static_info or "pmu:<type>:<config>:<config1>:<config2>" (values in hexadecimal)
+// for events resolved by the caller (pmu events from /sys/bus/event_source/devices, raw events), returns 0 if the event is unknown
+// the event may be followed by modifier :u (user mode only) or :k (kernel mode only), token is modified to remove it
+static int parse_event(char *token, counter_option *event) {
+  unsigned int type;
+  unsigned long long config, config1, config2;
+  bool exclude_user = false;
+  bool exclude_kernel = false;
+  char *modifier = strrchr(token, ':');
+  if (modifier != NULL && (strcmp(modifier, ":u") == 0 || strcmp(modifier, ":k") == 0)) {
+    exclude_kernel = modifier[1] == 'u';
+    exclude_user = modifier[1] == 'k';
+    *modifier = '\0';
+  }
+  if (strncmp(token, "pmu:", 4) == 0) {
+    if (sscanf(token, "pmu:%x:%llx:%llx:%llx", &type, &config, &config1, &config2) != 4)
+      return 0;
+    event->name = NULL;
+    event->perf_type = type;
+    event->perf_key = config;
+    event->perf_config1 = config1;
+    event->perf_config2 = config2;
+    event->exclude_user = exclude_user;
+    event->exclude_kernel = exclude_kernel;
+    return 1;
+  }
+  for(unsigned int i=0; i<nb_counter_option; i++) {
+    if(strcmp(perf_static_info[i].name, token) == 0) {
+      *event = perf_static_info[i];
+      event->exclude_user = exclude_user;
+      event->exclude_kernel = exclude_kernel;
+      return 1;
+    }
+  }
+  return 0;
+}
 
-// parse a string containing metric names in natural language (ex : "instruction,cpu_cycles,cache_misses") and fill perf_indexes with the corresponding indexes of perf_static_info
-void perf_event_list(char *perf_string, int *nb_perf, int **perf_indexes) {
-  char *token;
+// parse a string containing metric names in natural language (ex : "instruction,cpu_cycles,cache_misses") and fill events with the corresponding events, see parse_event
+// events joined by '+' form a group (ex : "instructions+cpu_cycles,cache_misses"), the first event of a group is its leader, see group_sizes in counter_t
+// returns -1 if all the events are known, otherwise the index of the first unknown event in perf_string and nothing is filled
+int perf_event_list(char *perf_string, int *nb_perf, counter_option **events, int **group_sizes) {
+  char *group;
+  char *group_saveptr;
+  int index = 0;
   *nb_perf=0;
-  *perf_indexes=NULL;
-  while((token=strtok(perf_string, ",")) != NULL) {
+  *events=NULL;
+  *group_sizes=NULL;
+  while((group=strtok_r(perf_string, ",", &group_saveptr)) != NULL) {
     perf_string = NULL;
-    int i;
-    for(i=0; i<nb_counter_option; i++) {
-      if(strcmp(perf_static_info[i].name, token) == 0) {
-	(*nb_perf)++;
-	(*perf_indexes) = realloc(*perf_indexes, sizeof(int)*(*nb_perf));
-	(*perf_indexes)[*nb_perf-1]=i;
-	break;
+    char *token;
+    char *token_saveptr;
+    int leader = -1;
+    while((token=strtok_r(group, "+", &token_saveptr)) != NULL) {
+      group = NULL;
+      counter_option event;
+      if (!parse_event(token, &event)) {
+        free(*events);
+        free(*group_sizes);
+        *nb_perf=0;
+        *events=NULL;
+        *group_sizes=NULL;
+        return index;
       }
-    }
-    if(i == nb_counter_option) {
-      fprintf(stdout, "Unknown performance counter: %s\n", token);
-      fflush(stdout);
-//      exit(EXIT_FAILURE);
+      index++;
+      (*nb_perf)++;
+      (*events) = realloc(*events, sizeof(counter_option)*(*nb_perf));
+      (*group_sizes) = realloc(*group_sizes, sizeof(int)*(*nb_perf));
+      (*events)[*nb_perf-1]=event;
+      (*group_sizes)[*nb_perf-1]=0;
+      if (leader == -1)
+        leader = *nb_perf-1;
+      (*group_sizes)[leader]++;
     }
   }
+  return -1;
 }
 
 
@@ -129,61 +248,115 @@ static long perf_event_open(struct perf_event_attr *hw_event, pid_t pid,
   return res;
 }
 
-char* concat(const char *s1, const char *s2)
-{
-    char *result = malloc(strlen(s1) + strlen(s2) + 1); // +1 for the null-terminator
-    if(result == NULL)
-      return NULL;
-    strcpy(result, s1);
-    strcat(result, s2);
-    return result;
+/* an event (or a group of events) is counted on all the cores or on none, the sum over the cores would be wrong otherwise,
+and a group whose member is missing on a core would return fewer values than expected there
+the events of a group with an error on any core are closed on all the cores, and the members without error get the error of the group */
+static void close_incomplete_groups(counter_t g_counter) {
+  for (int i=0; i<g_counter->nbperf; i+=g_counter->group_sizes[i]) {
+    int group_size = g_counter->group_sizes[i];
+    int group_errno = 0;
+    for (int j=0; j<group_size && group_errno == 0; j++)
+      group_errno = g_counter->errors[i+j];
+    if (group_errno == 0)
+      continue;
+    for (int j=0; j<group_size; j++) {
+      for (int core=0; core<g_counter->nbcores; core++) {
+        if (g_counter->counters[i+j][core] >= 0)
+          close(g_counter->counters[i+j][core]);
+        g_counter->counters[i+j][core] = -1;
+      }
+      if (g_counter->errors[i+j] == 0)
+        g_counter->errors[i+j] = group_errno;
+    }
+  }
 }
 
-/* open perf_event cgroup and initialize the counter by calling perv_event_open */
-counter_t init_counters(char *cgroup_name, int *perf_indexes) {
+/* open perf_event cgroup and initialize the counter by calling perv_event_open, the counter owns events and group_sizes
+cgroup_name is the path of the directory of the cgroup in the perf_event hierarchy (the job cgroup of the unified hierarchy with cgroup v2)
+cpus are the ids of the online cpus, they may not be contiguous */
+counter_t init_counters(char *cgroup_name, int nb_perf, counter_option *events, int *group_sizes, int *cpus, int nb_cpus) {
   struct perf_event_attr pe;
-  unsigned int nbcores = sysconf(_SC_NPROCESSORS_ONLN);
+  int nbcores = nb_cpus;
   memset(&pe, 0, sizeof(struct perf_event_attr));
   pe.size = sizeof(struct perf_event_attr);
   pe.disabled = 1;
 
-  char * filename = concat("/sys/fs/cgroup/perf_event", cgroup_name);
-  int fd1 = open(filename, O_RDONLY);
+  int fd1 = open(cgroup_name, O_RDONLY);
   if (fd1 < 0)
   {
+      // errno is kept for the caller
       return NULL;
   }
-  //printf("Gathering infos for: %s\n", filename);
+  //printf("Gathering infos for: %s\n", cgroup_name);
   //fflush(stdout);
 
-  counter_t g_counter = malloc(sizeof(counter_t));
+  counter_t g_counter = malloc(sizeof(struct _counter_t));
   g_counter->nbperf = nb_perf;
   g_counter->nbcores=nbcores;
+  g_counter->cpus = malloc(nbcores*sizeof(int));
+  memcpy(g_counter->cpus, cpus, nbcores*sizeof(int));
+  g_counter->events = events;
+  g_counter->group_sizes = group_sizes;
+  g_counter->errors = calloc(nb_perf, sizeof(int));
   g_counter->counters=malloc(nb_perf*sizeof(int*));
+  g_counter->time_enabled=malloc(nb_perf*sizeof(unsigned long long*));
+  g_counter->time_running=malloc(nb_perf*sizeof(unsigned long long*));
+  int leader = 0;
   for (int i=0; i<nb_perf; i++) {
-    int index = perf_indexes[i];
-    pe.type = perf_static_info[index].perf_type;
-    pe.config = perf_static_info[index].perf_key;
+    if (group_sizes[i] > 0)
+      leader = i;
+    bool grouped = group_sizes[leader] > 1;
+    pe.type = events[i].perf_type;
+    pe.config = events[i].perf_key;
+    pe.config1 = events[i].perf_config1;
+    pe.config2 = events[i].perf_config2;
+    // as perf does, the hypervisor is excluded when a mode is selected
+    pe.exclude_user = events[i].exclude_user;
+    pe.exclude_kernel = events[i].exclude_kernel;
+    pe.exclude_hv = events[i].exclude_user || events[i].exclude_kernel;
+    // when there are more events than hardware counters the kernel multiplexes them, the times are needed to scale the values
+    // the events of a group are scheduled together on the hardware counters, a read on the leader returns all their values
+    pe.read_format = PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING | (grouped ? PERF_FORMAT_GROUP : 0);
     g_counter->counters[i] = malloc(nbcores*sizeof(int));
+    g_counter->time_enabled[i] = calloc(nbcores, sizeof(unsigned long long));
+    g_counter->time_running[i] = calloc(nbcores, sizeof(unsigned long long));
 
     // loop on cores because per-cgroup monitoring is not available with cpu = -1 : https://stackoverflow.com/questions/52892668/using-perf-event-open-to-monitor-docker-containers
     // in cgroup-mode the event is measured only if the thread running on the monitored CPU belongs to the designated cgroup http://man7.org/linux/man-pages/man2/perf_event_open.2.html
     for (int core=0; core<nbcores; core++) {
-      g_counter->counters[i][core] = perf_event_open(&pe, fd1, core, -1, PERF_FLAG_PID_CGROUP|PERF_FLAG_FD_CLOEXEC);
+      int group_fd = (leader == i) ? -1 : g_counter->counters[leader][core];
+      if (leader != i && group_fd < 0) {
+        // the leader could not be opened, the event would not be part of the group
+        g_counter->counters[i][core] = -1;
+        continue;
+      }
+      g_counter->counters[i][core] = perf_event_open(&pe, fd1, g_counter->cpus[core], group_fd, PERF_FLAG_PID_CGROUP|PERF_FLAG_FD_CLOEXEC);
+      // the first error is kept
+      if (g_counter->counters[i][core] < 0 && g_counter->errors[i] == 0)
+        g_counter->errors[i] = errno;
     }
   }
   close(fd1);
-  free(filename);
+  close_incomplete_groups(g_counter);
   return g_counter;
 }
 
 void clean_counters(counter_t g_counter) {
   for(int counter=0; counter<g_counter->nbperf; counter++) {
     for(int core=0; core<g_counter->nbcores; core++)
-      close(g_counter->counters[counter][core]);
+      if (g_counter->counters[counter][core] >= 0)
+        close(g_counter->counters[counter][core]);
     free(g_counter->counters[counter]);
+    free(g_counter->time_enabled[counter]);
+    free(g_counter->time_running[counter]);
   }
   free(g_counter->counters);
+  free(g_counter->cpus);
+  free(g_counter->time_enabled);
+  free(g_counter->time_running);
+  free(g_counter->events);
+  free(g_counter->group_sizes);
+  free(g_counter->errors);
   free(g_counter);
 }
 
@@ -211,41 +384,175 @@ counter_t reset_counters(counter_t g_counter) {
   return g_counter;
 }
 
-int get_counters(long long *values, char *cgroup_name) {
+/* read the counters of every core, the value of event i on core c (cpu cpus[c]) is stored in values_per_core[i*nbcores+c]
+values are scaled by time_enabled/time_running since the last read when the event was multiplexed
+the fraction of time event i was counting is stored in running_ratios[i] if running_ratios is not NULL
+the value is 0 on the cores where the event could not be opened, returns -errno if a read fails, 0 otherwise */
+static int read_counters(counter_t g_counter, long long *values_per_core, double *running_ratios) {
+  for(int i=0; i<g_counter->nbperf; i+=g_counter->group_sizes[i]) {
+    int group_size = g_counter->group_sizes[i];
+    unsigned long long total_enabled = 0;
+    unsigned long long total_running = 0;
+    for (int core=0; core<g_counter->nbcores; core++) {
+      unsigned long long counts[group_size];
+      unsigned long long time_enabled;
+      memset(counts, 0, sizeof(counts));
+      unsigned long long time_running;
+      if (g_counter->counters[i][core] < 0) {
+        for (int j=0; j<group_size; j++)
+          values_per_core[(i+j)*g_counter->nbcores+core] = 0;
+        continue;
+      }
+      if (group_size > 1) {
+        // layout of PERF_FORMAT_GROUP : nr, time_enabled, time_running, then the value of each event of the group
+        unsigned long long group_format[3+group_size];
+        if (-1 == read(g_counter->counters[i][core], group_format, sizeof(group_format))) {
+          return -errno;
+        }
+        time_enabled = group_format[1];
+        time_running = group_format[2];
+        // the kernel returns the values of the events attached to the leader, the missing ones stay 0
+        for (int j=0; j<group_size && j<(int)group_format[0]; j++)
+          counts[j] = group_format[3+j];
+      } else {
+        struct read_format count;
+        if (-1 == read(g_counter->counters[i][core], &count, sizeof(struct read_format))) {
+          //printf("Error reading counter values \n");
+          //fflush(stdout);
+          return -errno;
+        }
+        time_enabled = count.time_enabled;
+        time_running = count.time_running;
+        counts[0] = count.value;
+      }
+      unsigned long long enabled = time_enabled - g_counter->time_enabled[i][core];
+      unsigned long long running = time_running - g_counter->time_running[i][core];
+      g_counter->time_enabled[i][core] = time_enabled;
+      g_counter->time_running[i][core] = time_running;
+      total_enabled += enabled;
+      total_running += running;
+
+      for (int j=0; j<group_size; j++) {
+        long long value = counts[j];
+        if (running > 0 && running < enabled) {
+          value = (long long)((double)counts[j] * enabled / running);
+        }
+        values_per_core[(i+j)*g_counter->nbcores+core] = value;
+      }
+    }
+    for (int j=0; running_ratios != NULL && j<group_size; j++) {
+      // the cgroup did not run during the interval, nothing was missed
+      running_ratios[i+j] = total_enabled > 0 ? (double)total_running / total_enabled : 1.0;
+    }
+  }
+  return 0;
+}
+
+/* values of the events of the cgroup summed over all cores, at most nb_values are written
+running_ratios (may be NULL) receives at most nb_values ratios, see read_counters
+returns the number of events of the cgroup, -ENOENT if the cgroup is not in the list, -errno if a read fails */
+int get_counters(long long *values, double *running_ratios, int nb_values, char *cgroup_name) {
   cgroup_t cgroup = find(cgroup_name);
   counter_t g_counter = NULL;
 
   if (cgroup == NULL) {
-     return -1;
+     return -ENOENT;
   } else {
      g_counter = cgroup->g_counter;
   }
 
-  for(int i=0; i<g_counter->nbperf; i++) {
+  long long *values_per_core = malloc(g_counter->nbperf*g_counter->nbcores*sizeof(long long));
+  double *ratios = malloc(g_counter->nbperf*sizeof(double));
+  int res = read_counters(g_counter, values_per_core, ratios);
+  if (res < 0) {
+    free(values_per_core);
+    free(ratios);
+    return res;
+  }
+  for(int i=0; i<g_counter->nbperf && i<nb_values; i++) {
     long long accu=0;
-    long long count;
-    for (int core=0; core<g_counter->nbcores; core++) {
-      if (-1 == read(g_counter->counters[i][core], &count, sizeof(long long))) {
-        //printf("Error reading counter values \n");
-        //fflush(stdout);
-        return -1;
-      }
-      accu += count;
-    }
+    for (int core=0; core<g_counter->nbcores; core++)
+      accu += values_per_core[i*g_counter->nbcores+core];
     values[i] = accu;
+    if (running_ratios != NULL)
+      running_ratios[i] = ratios[i];
   }
+  free(values_per_core);
+  free(ratios);
   reset_counters(g_counter);
-  return 0;
+  return g_counter->nbperf;
 }
 
+/* values of the events of the cgroup on each core (see read_counters for the layout), at most nb_values are written
+running_ratios (may be NULL) receives one ratio per event, at most nb_values/nbcores are written
+returns the number of events of the cgroup, -ENOENT if the cgroup is not in the list, -errno if a read fails */
+int get_counters_per_core(long long *values, double *running_ratios, int nb_values, char *cgroup_name) {
+  cgroup_t cgroup = find(cgroup_name);
+  counter_t g_counter = NULL;
+
+  if (cgroup == NULL) {
+     return -ENOENT;
+  } else {
+     g_counter = cgroup->g_counter;
+  }
+
+  int nb_counters = g_counter->nbperf*g_counter->nbcores;
+  long long *values_per_core = malloc(nb_counters*sizeof(long long));
+  double *ratios = malloc(g_counter->nbperf*sizeof(double));
+  int res = read_counters(g_counter, values_per_core, ratios);
+  if (res < 0) {
+    free(values_per_core);
+    free(ratios);
+    return res;
+  }
+  memcpy(values, values_per_core, (nb_counters < nb_values ? nb_counters : nb_values)*sizeof(long long));
+  for(int i=0; running_ratios != NULL && i<g_counter->nbperf && i<nb_values/g_counter->nbcores; i++)
+    running_ratios[i] = ratios[i];
+  free(values_per_core);
+  free(ratios);
+  reset_counters(g_counter);
+  return g_counter->nbperf;
+}
+
+/* number of file descriptors opened by perf_event_open for the cgroup, -1 if the cgroup is not in the list */
+int get_nb_counters(char *cgroup_name) {
+  cgroup_t cgroup = find(cgroup_name);
+  if (cgroup == NULL) {
+     return -1;
+  }
+  counter_t g_counter = cgroup->g_counter;
+  int nb_counters = 0;
+  for(int counter=0; counter<g_counter->nbperf; counter++)
+    for(int core=0; core<g_counter->nbcores; core++)
+      if (g_counter->counters[counter][core] >= 0)
+        nb_counters++;
+  return nb_counters;
+}
+
+/* errno of perf_event_open for each event of the cgroup, 0 if the event is opened on all the cores, at most nb_values are written
+returns the number of events of the cgroup, -ENOENT if the cgroup is not in the list */
+int get_counter_errors(int *errors, int nb_values, char *cgroup_name) {
+  cgroup_t cgroup = find(cgroup_name);
+  if (cgroup == NULL) {
+     return -ENOENT;
+  }
+  counter_t g_counter = cgroup->g_counter;
+  memcpy(errors, g_counter->errors, (g_counter->nbperf < nb_values ? g_counter->nbperf : nb_values)*sizeof(int));
+  return g_counter->nbperf;
+}
+
+
 
 
 
 
 
 // insert the cgroup in the list, init and start its counters
-// if the cgroup is already in the list, does nothing
-int init_cgroup(char *cgroup_name, char *metrics) {
+// if the cgroup is already in the list with the same metrics, does nothing, with other metrics its counters are opened again
+// the counters are opened on each of the nb_cpus cpus, whose ids are in cpus
+// returns 1 if the cgroup was already in the list, 2 if its counters were opened, -errno if the perf_event cgroup could not be opened
+// and -EINVAL if an event is unknown, its index in metrics (see perf_event_list) is then written in unknown_event
+int init_cgroup(char *cgroup_name, char *metrics, int *cpus, int nb_cpus, int *unknown_event) {
 
     //printf("Cgroup name received %s \n", cgroup_name);
     //fflush(stdout);
@@ -253,25 +560,31 @@ int init_cgroup(char *cgroup_name, char *metrics) {
     //fflush(stdout);
     cgroup_t cgroup = find(cgroup_name);
     if (cgroup != NULL) {
-        return 1;
-    } else {
-        char *metrics_copy = strdup(metrics);
-        perf_event_list(metrics_copy, &nb_perf, &perf_indexes);
-	//printf("metric_copy : %s \n", metrics_copy);
-	//fflush(stdout);
-	//printf("nb_perf : %d \n", nb_perf);
-	//fflush(stdout);
-	//printf("perf_indexes[0] : %d \n", perf_indexes[0]);
-	//fflush(stdout);
-        free(metrics_copy);
-        counter_t g_counter = init_counters(cgroup_name, perf_indexes);
-        if (g_counter != NULL) {
-            insertFirst(cgroup_name, g_counter);
-            start_counters(cgroup_name);
-            return 2;
-        } else {
-            return 3;
+        if (strcmp(cgroup->metrics, metrics) == 0) {
+            return 1;
         }
+        remove_cgroup(cgroup_name);
+    }
+    int nb_perf;
+    counter_option *events;
+    int *group_sizes;
+    char *metrics_copy = strdup(metrics);
+    int unknown = perf_event_list(metrics_copy, &nb_perf, &events, &group_sizes);
+    free(metrics_copy);
+    if (unknown >= 0) {
+        *unknown_event = unknown;
+        return -EINVAL;
+    }
+    counter_t g_counter = init_counters(cgroup_name, nb_perf, events, group_sizes, cpus, nb_cpus);
+    if (g_counter != NULL) {
+        insertFirst(cgroup_name, metrics, g_counter);
+        start_counters(cgroup_name);
+        return 2;
+    } else {
+        int open_errno = errno;
+        free(events);
+        free(group_sizes);
+        return -open_errno;
     }
 }
 
@@ -287,9 +600,10 @@ void printList() {
 }
 
 // insert a cgroup at the beginning of the cgroup list
-void insertFirst(char *cgroup_name, counter_t g_counter) {
-   cgroup_t link = (cgroup_t) malloc(sizeof(cgroup_t));
+void insertFirst(char *cgroup_name, char *metrics, counter_t g_counter) {
+   cgroup_t link = (cgroup_t) malloc(sizeof(struct _cgroup_t));
    link->cgroup_name = strdup(cgroup_name);
+   link->metrics = strdup(metrics);
    link->g_counter = g_counter;
    link->next = head;
    head = link;
@@ -320,6 +634,7 @@ void clean_cgroup(cgroup_t cgroup) {
      g_counter = cgroup->g_counter;
   }
   free(cgroup->cgroup_name);
+  free(cgroup->metrics);
   clean_counters(g_counter);
   free(cgroup);
 }
@@ -346,20 +661,3 @@ void remove_cgroup(char *cgroup_name) {
    }
    clean_cgroup(current);
 }
-
-/*void main(){
-    char options[] = "page_faults,instructions,cache_node,instructions,instructions";
-    init_cgroup("/test1", options);
-    char options2[] = "instructions,page_faults,cache_node";
-    init_cgroup("/test2", options2);
-
-
-    long long *counters1 = malloc(sizeof(long long)*3);
-    long long *counters2 = malloc(sizeof(long long)*3);
-
-    get_counters(counters1, "/test1");
-    get_counters(counters2, "/test2");
-
-    printList();
-    printf("ok");
-}*/
@@ -0,0 +1,307 @@
+use std::collections::BTreeMap;
+
+use crate::backends::metric::MetricValue;
+
+// values sampled during an aggregation window are sent once per window as min, max, mean, last, sum and/or percentiles
+// last is sent with the name of the metric, the other aggregations as metric_name.aggregation (ex rss.max@instance)
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Min,
+    Max,
+    Mean,
+    Last,
+    Sum,
+    P50,
+    P90,
+    P99,
+}
+
+impl Aggregation {
+    fn name(&self) -> &'static str {
+        match self {
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Mean => "mean",
+            Aggregation::Last => "last",
+            Aggregation::Sum => "sum",
+            Aggregation::P50 => "p50",
+            Aggregation::P90 => "p90",
+            Aggregation::P99 => "p99",
+        }
+    }
+
+    fn quantile(&self) -> Option<f64> {
+        match self {
+            Aggregation::P50 => Some(0.5),
+            Aggregation::P90 => Some(0.9),
+            Aggregation::P99 => Some(0.99),
+            _ => None,
+        }
+    }
+
+    pub fn is_percentile(&self) -> bool {
+        self.quantile().is_some()
+    }
+
+    // name of the aggregated metric, metric_name is the name without instance
+    pub fn metric_name(&self, metric_name: &str) -> String {
+        match self {
+            Aggregation::Last => metric_name.to_string(),
+            _ => format!("{}.{}", metric_name, self.name()),
+        }
+    }
+}
+
+// parses metric_name=aggregation+aggregation,... (ex rss=max+mean,pgfault=sum), None if an aggregation is unknown
+pub fn parse_metric_aggregations(aggregations: &str) -> Option<Vec<(String, Vec<Aggregation>)>> {
+    let mut res = Vec::new();
+    for a in aggregations.split(',').map(|a| a.trim()).filter(|a| !a.is_empty()) {
+        let (metric_name, list) = a.split_once('=')?;
+        let mut metric_aggregations = Vec::new();
+        for aggregation in list.split('+') {
+            metric_aggregations.push(match aggregation.trim() {
+                "min" => Aggregation::Min,
+                "max" => Aggregation::Max,
+                "mean" => Aggregation::Mean,
+                "last" => Aggregation::Last,
+                "sum" => Aggregation::Sum,
+                "p50" => Aggregation::P50,
+                "p90" => Aggregation::P90,
+                "p99" => Aggregation::P99,
+                _ => return None,
+            });
+        }
+        res.push((metric_name.trim().to_string(), metric_aggregations));
+    }
+    Some(res)
+}
+
+// relative error of the percentiles
+const SKETCH_RELATIVE_ACCURACY: f64 = 0.01;
+// absolute values lower than this are counted as 0
+const SKETCH_MIN_VALUE: f64 = 1e-9;
+
+// quantile sketch with logarithmic buckets (as DDSketch): a value v > 0 is counted in bucket ceil(log(v) / log(gamma)),
+// the quantiles are within SKETCH_RELATIVE_ACCURACY of the exact ones, whatever the number of values
+// at most about 1400 buckets for values from 1 to 10^12, usually far fewer since the values of a window are close
+#[derive(Debug, Clone)]
+pub struct QuantileSketch {
+    ln_gamma: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+}
+
+impl QuantileSketch {
+    pub fn new() -> QuantileSketch {
+        let gamma = (1. + SKETCH_RELATIVE_ACCURACY) / (1. - SKETCH_RELATIVE_ACCURACY);
+        QuantileSketch { ln_gamma: gamma.ln(), positive: BTreeMap::new(), negative: BTreeMap::new(), zero: 0, count: 0 }
+    }
+
+    fn index(&self, v: f64) -> i32 {
+        (v.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    // value in the middle of the bucket, within the relative accuracy of all its values
+    fn bucket_value(&self, index: i32) -> f64 {
+        let gamma = self.ln_gamma.exp();
+        2. * (self.ln_gamma * index as f64).exp() / (gamma + 1.)
+    }
+
+    pub fn add(&mut self, v: f64) {
+        if v.is_nan() {
+            return;
+        }
+        if v > SKETCH_MIN_VALUE {
+            *self.positive.entry(self.index(v)).or_insert(0) += 1;
+        } else if v < -SKETCH_MIN_VALUE {
+            *self.negative.entry(self.index(-v)).or_insert(0) += 1;
+        } else {
+            self.zero += 1;
+        }
+        self.count += 1;
+    }
+
+    // q between 0 and 1, None if the sketch is empty
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q * (self.count - 1) as f64).floor() as u64;
+        let mut seen = 0;
+        // from the lowest value: negative values by decreasing absolute value, 0, then positive values
+        for (index, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return Some(-self.bucket_value(*index));
+            }
+        }
+        seen += self.zero;
+        if seen > rank {
+            return Some(0.);
+        }
+        for (index, count) in self.positive.iter() {
+            seen += count;
+            if seen > rank {
+                return Some(self.bucket_value(*index));
+            }
+        }
+        None
+    }
+}
+
+// values of a metric during the current window, integers stay integers except for the mean and the percentiles
+#[derive(Debug, Clone)]
+pub struct WindowStats {
+    min: MetricValue,
+    max: MetricValue,
+    sum: MetricValue,
+    last: MetricValue,
+    count: u64,
+    // only for the metrics whose percentiles are sent
+    sketch: Option<QuantileSketch>,
+}
+
+impl WindowStats {
+    pub fn new(value: MetricValue, with_percentiles: bool) -> WindowStats {
+        let sketch = if with_percentiles {
+            let mut sketch = QuantileSketch::new();
+            sketch.add(value.as_f64());
+            Some(sketch)
+        } else {
+            None
+        };
+        WindowStats { min: value, max: value, sum: value, last: value, count: 1, sketch }
+    }
+
+    pub fn add(&mut self, value: MetricValue) {
+        if value.as_f64() < self.min.as_f64() {
+            self.min = value;
+        }
+        if value.as_f64() > self.max.as_f64() {
+            self.max = value;
+        }
+        self.sum = match (self.sum, value) {
+            (MetricValue::Int(s), MetricValue::Int(v)) => MetricValue::Int(s.saturating_add(v)),
+            (s, v) => MetricValue::Float(s.as_f64() + v.as_f64()),
+        };
+        self.last = value;
+        self.count += 1;
+        if let Some(sketch) = &mut self.sketch {
+            sketch.add(value.as_f64());
+        }
+    }
+
+    pub fn value(&self, aggregation: Aggregation) -> MetricValue {
+        match aggregation {
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+            Aggregation::Mean => MetricValue::Float(self.sum.as_f64() / self.count as f64),
+            Aggregation::Last => self.last,
+            Aggregation::Sum => self.sum,
+            percentile => {
+                let q = percentile.quantile().unwrap();
+                let v = self.sketch.as_ref().and_then(|s| s.quantile(q)).unwrap_or(self.last.as_f64());
+                // the bucket value may be slightly out of the range of the values
+                MetricValue::Float(v.clamp(self.min.as_f64(), self.max.as_f64()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // exact quantile with the same rank as QuantileSketch::quantile
+    fn exact_quantile(values: &[f64], q: f64) -> f64 {
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        sorted[(q * (sorted.len() - 1) as f64).floor() as usize]
+    }
+
+    fn assert_close(value: f64, expected: f64) {
+        assert!((value - expected).abs() <= SKETCH_RELATIVE_ACCURACY * expected.abs(), "{} instead of {}", value, expected);
+    }
+
+    fn sketch_of(values: &[f64]) -> QuantileSketch {
+        let mut sketch = QuantileSketch::new();
+        for v in values {
+            sketch.add(*v);
+        }
+        sketch
+    }
+
+    // values spread over several orders of magnitude, without a random number generator
+    fn values(n: u64, offset: f64) -> Vec<f64> {
+        (0..n).map(|i| offset + ((i * 7919) % n) as f64 * ((i % 13) as f64 + 1.).powi(3)).collect()
+    }
+
+    #[test]
+    fn test_quantiles() {
+        for values in [values(1000, 1.), values(10007, 0.5), (1..=100).map(|i| i as f64).collect()] {
+            let sketch = sketch_of(&values);
+            for q in [0.5, 0.9, 0.99] {
+                assert_close(sketch.quantile(q).unwrap(), exact_quantile(&values, q));
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_and_negative_values() {
+        let values: Vec<f64> = (-50..=50).map(|i| i as f64 * 3.).collect();
+        let sketch = sketch_of(&values);
+        assert_eq!(sketch.quantile(0.5), Some(0.));
+        assert_close(sketch.quantile(0.1).unwrap(), exact_quantile(&values, 0.1));
+        assert_close(sketch.quantile(0.99).unwrap(), exact_quantile(&values, 0.99));
+
+        let sketch = sketch_of(&[0., 0., 0.]);
+        assert_eq!(sketch.quantile(0.99), Some(0.));
+        let sketch = sketch_of(&[-10., -20., -30.]);
+        assert_close(sketch.quantile(0.5).unwrap(), -20.);
+        // NaN is ignored
+        let sketch = sketch_of(&[f64::NAN, 5.]);
+        assert_close(sketch.quantile(0.5).unwrap(), 5.);
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(QuantileSketch::new().quantile(0.5), None);
+        assert_eq!(sketch_of(&[f64::NAN]).quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_window_stats() {
+        let mut stats = WindowStats::new(MetricValue::Int(3), true);
+        for v in [1, 4, 1, 5] {
+            stats.add(MetricValue::Int(v));
+        }
+        assert!(matches!(stats.value(Aggregation::Min), MetricValue::Int(1)));
+        assert!(matches!(stats.value(Aggregation::Max), MetricValue::Int(5)));
+        assert!(matches!(stats.value(Aggregation::Sum), MetricValue::Int(14)));
+        assert!(matches!(stats.value(Aggregation::Last), MetricValue::Int(5)));
+        assert_close(stats.value(Aggregation::Mean).as_f64(), 2.8);
+        assert_close(stats.value(Aggregation::P50).as_f64(), 3.);
+        assert_close(stats.value(Aggregation::P99).as_f64(), 4.);
+        // the percentiles stay within the values of the window
+        let stats = WindowStats::new(MetricValue::Int(7), true);
+        assert_eq!(stats.value(Aggregation::P90).as_f64(), 7.);
+        // without sketch the percentiles are the last value
+        let stats = WindowStats::new(MetricValue::Int(7), false);
+        assert_eq!(stats.value(Aggregation::P90).as_f64(), 7.);
+    }
+
+    #[test]
+    fn test_parse_metric_aggregations() {
+        let res = parse_metric_aggregations("rss=max+p99, pgfault=sum").unwrap();
+        assert_eq!(res, vec![
+            ("rss".to_string(), vec![Aggregation::Max, Aggregation::P99]),
+            ("pgfault".to_string(), vec![Aggregation::Sum]),
+        ]);
+        assert!(parse_metric_aggregations("rss=p95").is_none());
+        assert!(parse_metric_aggregations("rss").is_none());
+        assert_eq!(parse_metric_aggregations("").unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::SystemTime;
+
+use yaml_rust::YamlLoader;
+
+use crate::backends::derived::DerivedMetric;
+use crate::event::Event;
+
+// alert rules evaluated on the node with the values collected for each job, defined in a yaml file:
+// alert_rules:
+//   memory_near_limit: rss / hierarchical_memory_limit > 0.9 for 30s
+// a rule is an expression (as derived metrics), a comparison with a threshold and an optional duration during which the
+// comparison must stay true before the alert fires, an event is sent when the alert fires and when it is resolved
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Lower,
+    LowerOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    fn parse(op: &str) -> Option<Comparison> {
+        match op {
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            "<" => Some(Comparison::Lower),
+            "<=" => Some(Comparison::LowerOrEqual),
+            "==" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            _ => None,
+        }
+    }
+
+    fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Greater => value > threshold,
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::Lower => value < threshold,
+            Comparison::LowerOrEqual => value <= threshold,
+            Comparison::Equal => value == threshold,
+            Comparison::NotEqual => value != threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    // the rule as written in the file
+    definition: String,
+    expression: DerivedMetric,
+    comparison: Comparison,
+    threshold: f64,
+    // milliseconds
+    duration: i64,
+}
+
+impl AlertRule {
+    // <expression> <comparison> <threshold> [for <duration>], the duration is a number followed by ms, s, m or h
+    pub fn new(name: &str, definition: &str) -> Result<AlertRule, String> {
+        let op_start = find_comparison(definition).ok_or("no comparison (>, >=, <, <=, ==, !=)")?;
+        let op_len = if definition[op_start + 1..].starts_with('=') { 2 } else { 1 };
+        let comparison = Comparison::parse(&definition[op_start..op_start + op_len])
+            .ok_or(format!("invalid comparison {}", &definition[op_start..op_start + op_len]))?;
+        let expression = DerivedMetric::new(name, &definition[..op_start])?;
+
+        let mut rest = definition[op_start + op_len..].split_whitespace();
+        let threshold = rest.next().ok_or("no threshold")?;
+        let threshold = threshold.parse::<f64>().map_err(|_| format!("invalid threshold {}", threshold))?;
+        let duration = match (rest.next(), rest.next(), rest.next()) {
+            (None, _, _) => 0,
+            (Some("for"), Some(duration), None) => parse_duration(duration).ok_or(format!("invalid duration {}", duration))?,
+            _ => return Err("expected 'for <duration>' after the threshold".to_string()),
+        };
+        Ok(AlertRule { name: name.to_string(), definition: definition.to_string(), expression, comparison, threshold, duration })
+    }
+}
+
+// position of the comparison, outside of the quoted metric names of the expression
+fn find_comparison(definition: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (i, c) in definition.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '<' | '>' | '=' | '!' if !quoted => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+// 30s -> 30000 (milliseconds)
+fn parse_duration(duration: &str) -> Option<i64> {
+    let split = duration.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let value = duration[..split].parse::<f64>().ok()?;
+    let factor = match &duration[split..] {
+        "ms" => 1.,
+        "s" => 1000.,
+        "m" => 60000.,
+        "h" => 3600000.,
+        _ => return None,
+    };
+    Some((value * factor) as i64)
+}
+
+// reads the rules of the file, in the order of the file
+pub fn load_alert_rules(filename: &str) -> Result<Vec<AlertRule>, String> {
+    let content = fs::read_to_string(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+    let doc = YamlLoader::load_from_str(&content)
+        .map_err(|e| format!("cannot parse {}: {}", filename, e))?
+        .pop()
+        .ok_or(format!("{} is empty", filename))?;
+    let definitions = doc["alert_rules"].as_hash().ok_or(format!("no alert_rules in {}", filename))?;
+    let mut res = Vec::new();
+    for (name, definition) in definitions {
+        let (name, definition) = match (name.as_str(), definition.as_str()) {
+            (Some(name), Some(definition)) => (name, definition),
+            _ => return Err(format!("alert rule {:?} of {} is not a name with a rule", name, filename)),
+        };
+        res.push(AlertRule::new(name, definition).map_err(|e| format!("alert rule {}: {}", name, e))?);
+    }
+    Ok(res)
+}
+
+#[derive(Debug, Clone, Default)]
+struct AlertState {
+    // timestamp at which the comparison became true, None while it is false
+    since: Option<i64>,
+    firing: bool,
+}
+
+// evaluates the rules for each job, the rules are read again when the file is modified
+pub struct AlertEngine {
+    filename: String,
+    modified: Option<SystemTime>,
+    rules: Vec<AlertRule>,
+    // (rule name, job_id) -> state
+    states: HashMap<(String, i32), AlertState>,
+}
+
+impl AlertEngine {
+    pub fn new(filename: &str) -> Result<AlertEngine, String> {
+        let modified = fs::metadata(filename).and_then(|m| m.modified()).ok();
+        let rules = load_alert_rules(filename)?;
+        Ok(AlertEngine { filename: filename.to_string(), modified, rules, states: HashMap::new() })
+    }
+
+    pub fn rules(&self) -> &[AlertRule] {
+        &self.rules
+    }
+
+    // replaces the rules if the file was modified, the previous rules are kept if the new file is invalid
+    // the state of the rules that did not change is kept
+    pub fn reload_if_modified(&mut self) {
+        let modified = fs::metadata(&self.filename).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match load_alert_rules(&self.filename) {
+            Ok(rules) => {
+                info!("alert rules reloaded from {}: {} rules", self.filename, rules.len());
+                let unchanged: Vec<&String> = rules.iter()
+                    .filter(|r| self.rules.iter().any(|old| old.name == r.name && old.definition == r.definition))
+                    .map(|r| &r.name)
+                    .collect();
+                self.states.retain(|(rule_name, _), _| unchanged.contains(&rule_name));
+                self.rules = rules;
+            }
+            Err(e) => error!("{}, keeping the previous alert rules", e),
+        }
+    }
+
+    // evaluates the rules of the job that use a metric of updated, returns the alerts that fired or were resolved
+    pub fn evaluate(&mut self, job_id: i32, values: &HashMap<String, f64>, updated: &[String], timestamp: i64, hostname: &str) -> Vec<Event> {
+        let mut events = Vec::new();
+        for rule in &self.rules {
+            if !rule.expression.operands.iter().any(|o| updated.contains(o)) {
+                continue;
+            }
+            // the state does not change while a metric of the rule has no value
+            let value = match rule.expression.compute(values) {
+                Some(value) => value,
+                None => continue,
+            };
+            let state = self.states.entry((rule.name.clone(), job_id)).or_default();
+            if rule.comparison.holds(value, rule.threshold) {
+                let since = *state.since.get_or_insert(timestamp);
+                if !state.firing && timestamp - since >= rule.duration {
+                    state.firing = true;
+                    warn!("alert {} firing for job {}: {} (value {})", rule.name, job_id, rule.definition, value);
+                    events.push(make_event(rule, job_id, value, "firing", since, timestamp, hostname));
+                }
+            } else {
+                if state.firing {
+                    info!("alert {} resolved for job {}: {} (value {})", rule.name, job_id, rule.definition, value);
+                    events.push(make_event(rule, job_id, value, "resolved", state.since.unwrap_or(timestamp), timestamp, hostname));
+                }
+                *state = AlertState::default();
+            }
+        }
+        events
+    }
+
+    pub fn remove_job(&mut self, job_id: i32) {
+        self.states.retain(|(_, j), _| *j != job_id);
+    }
+}
+
+fn make_event(rule: &AlertRule, job_id: i32, value: f64, state: &str, since: i64, timestamp: i64, hostname: &str) -> Event {
+    let mut details = HashMap::new();
+    details.insert("rule".to_string(), rule.name.clone());
+    details.insert("definition".to_string(), rule.definition.clone());
+    details.insert("state".to_string(), state.to_string());
+    details.insert("value".to_string(), value.to_string());
+    details.insert("since".to_string(), since.to_string());
+    Event {
+        event_type: "alert".to_string(),
+        hostname: hostname.to_string(),
+        timestamp,
+        job_id,
+        details,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn values(v: &[(&str, f64)]) -> HashMap<String, f64> {
+        v.iter().map(|(n, v)| (n.to_string(), *v)).collect()
+    }
+
+    fn states(events: &[Event]) -> Vec<(String, String)> {
+        events.iter().map(|e| (e.details["rule"].clone(), e.details["state"].clone())).collect()
+    }
+
+    fn engine(content: &str) -> (tempdir::TempDir, AlertEngine) {
+        let dir = tempdir::TempDir::new("colmet-alert").unwrap();
+        let filename = dir.path().join("alert_rules.yml");
+        fs::write(&filename, content).unwrap();
+        let engine = AlertEngine::new(filename.to_str().unwrap()).unwrap();
+        (dir, engine)
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for definition in ["rss", "rss > ", "rss > high", "rss =< 1", "rss <> 1", "rss + > 1", "rss > 1 for", "rss > 1 during 30s",
+                           "rss > 1 for 30", "rss > 1 for 30d", "rss > 1 for 30s 1m"] {
+            assert!(AlertRule::new("test", definition).is_err(), "{}", definition);
+        }
+        let rule = AlertRule::new("test", "rss / limit >= 0.9 for 1.5m").unwrap();
+        assert_eq!((rule.comparison, rule.threshold, rule.duration), (Comparison::GreaterOrEqual, 0.9, 90000));
+        assert_eq!(parse_duration("250ms"), Some(250));
+        assert_eq!(parse_duration("2h"), Some(7200000));
+        assert_eq!(parse_duration("s"), None);
+    }
+
+    #[test]
+    fn test_quoted_names() {
+        let rule = AlertRule::new("test", "\"nfs_read_rtt@/home\" / \"cpu/mem-loads/\" > 2").unwrap();
+        assert_eq!(rule.expression.operands, vec!["nfs_read_rtt@/home".to_string(), "cpu/mem-loads/".to_string()]);
+        assert_eq!((rule.comparison, rule.threshold), (Comparison::Greater, 2.));
+        // comparison characters in a quoted name are part of the name
+        let rule = AlertRule::new("test", "\"a>b\" != 0").unwrap();
+        assert_eq!(rule.expression.operands, vec!["a>b".to_string()]);
+        assert_eq!(rule.comparison, Comparison::NotEqual);
+        assert!(AlertRule::new("test", "\"rss > 1").is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let cases = [
+            (">", [false, false, true]),
+            (">=", [false, true, true]),
+            ("<", [true, false, false]),
+            ("<=", [true, true, false]),
+            ("==", [false, true, false]),
+            ("!=", [true, false, true]),
+        ];
+        for (op, expected) in cases {
+            let rule = AlertRule::new("test", &format!("rss {} 10", op)).unwrap();
+            for (value, expected) in [9., 10., 11.].iter().zip(expected) {
+                assert_eq!(rule.comparison.holds(*value, rule.threshold), expected, "{} {} 10", value, op);
+            }
+        }
+    }
+
+    #[test]
+    fn test_duration_and_resolution() {
+        let (_dir, mut engine) = engine("alert_rules:\n  high_rss: rss > 10 for 30s\n");
+        let updated = vec!["rss".to_string()];
+        // the comparison must hold for 30s before the alert fires
+        assert!(engine.evaluate(1, &values(&[("rss", 11.)]), &updated, 0, "node").is_empty());
+        assert!(engine.evaluate(1, &values(&[("rss", 12.)]), &updated, 20000, "node").is_empty());
+        let events = engine.evaluate(1, &values(&[("rss", 12.)]), &updated, 30000, "node");
+        assert_eq!(states(&events), vec![("high_rss".to_string(), "firing".to_string())]);
+        assert_eq!(events[0].details["since"], "0");
+        // it fires once, then is resolved once
+        assert!(engine.evaluate(1, &values(&[("rss", 13.)]), &updated, 40000, "node").is_empty());
+        let events = engine.evaluate(1, &values(&[("rss", 5.)]), &updated, 50000, "node");
+        assert_eq!(states(&events), vec![("high_rss".to_string(), "resolved".to_string())]);
+        assert!(engine.evaluate(1, &values(&[("rss", 5.)]), &updated, 60000, "node").is_empty());
+        // a value that goes back under the threshold before 30s starts the duration again
+        assert!(engine.evaluate(1, &values(&[("rss", 11.)]), &updated, 70000, "node").is_empty());
+        assert!(engine.evaluate(1, &values(&[("rss", 5.)]), &updated, 80000, "node").is_empty());
+        assert!(engine.evaluate(1, &values(&[("rss", 11.)]), &updated, 90000, "node").is_empty());
+        assert!(engine.evaluate(1, &values(&[("rss", 11.)]), &updated, 110000, "node").is_empty());
+        // the rules are not evaluated when their metrics are not updated, and each job has its own state
+        assert!(engine.evaluate(1, &values(&[("rss", 11.)]), &["cache".to_string()], 200000, "node").is_empty());
+        assert!(engine.evaluate(2, &values(&[("rss", 11.)]), &updated, 200000, "node").is_empty());
+        assert_eq!(engine.evaluate(1, &values(&[("rss", 11.)]), &updated, 200000, "node").len(), 1);
+    }
+
+    #[test]
+    fn test_reload() {
+        let (dir, mut engine) = engine("alert_rules:\n  high_rss: rss > 10\n  low_rss: rss < 5\n");
+        let updated = vec!["rss".to_string()];
+        assert_eq!(engine.evaluate(1, &values(&[("rss", 11.)]), &updated, 0, "node").len(), 1);
+
+        // the file is read again only when its mtime changes
+        let filename = dir.path().join("alert_rules.yml");
+        fs::write(&filename, "alert_rules:\n  high_rss: rss > 10\n  low_rss: rss < 8\n  zero_rss: rss == 0\n").unwrap();
+        let modified = engine.modified.unwrap();
+        fs::File::options().write(true).open(&filename).unwrap().set_modified(modified).unwrap();
+        engine.reload_if_modified();
+        assert_eq!(engine.rules().len(), 2);
+        fs::File::options().write(true).open(&filename).unwrap().set_modified(modified + Duration::from_secs(10)).unwrap();
+        engine.reload_if_modified();
+        assert_eq!(engine.rules().iter().map(|r| r.name.as_str()).collect::<Vec<_>>(), vec!["high_rss", "low_rss", "zero_rss"]);
+        // the state of the unchanged rule is kept: it does not fire again
+        assert!(engine.evaluate(1, &values(&[("rss", 12.)]), &updated, 1000, "node").is_empty());
+        assert_eq!(states(&engine.evaluate(1, &values(&[("rss", 7.)]), &updated, 2000, "node")), vec![
+            ("high_rss".to_string(), "resolved".to_string()),
+            ("low_rss".to_string(), "firing".to_string()),
+        ]);
+
+        // an invalid file keeps the previous rules
+        fs::write(&filename, "alert_rules:\n  high_rss: rss >\n").unwrap();
+        fs::File::options().write(true).open(&filename).unwrap().set_modified(modified + Duration::from_secs(20)).unwrap();
+        engine.reload_if_modified();
+        assert_eq!(engine.rules().len(), 3);
+    }
+}
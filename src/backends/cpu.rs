@@ -6,6 +6,7 @@ use std::io::Read;
 use std::sync::Arc;
 
 use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
 use crate::backends::metric::MetricValues;
 use crate::backends::Backend;
 use crate::cgroup_manager::CgroupManager;
@@ -71,7 +72,7 @@ fn return_values(&self, mut metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMa
                     job_id: cgroup_id,
                     backend_name: self.backend_name.clone(),
                     metric_names: m_names,
-                    metric_values,
+                    metric_values: metric_values.into_iter().map(MetricValue::Int).collect(),
                 };
                 ret.insert(cgroup_id, metric);
             }
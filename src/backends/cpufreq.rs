@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
+use crate::backends::metric::MetricValues;
+use crate::backends::{get_job_metrics, Backend};
+use crate::cgroup_manager::CgroupManager;
+use crate::utils::parse_cpu_list;
+
+pub struct CpufreqBackend {
+    pub backend_name: String,
+    cgroup_manager: Arc<CgroupManager>,
+}
+
+impl CpufreqBackend {
+    pub fn new(cgroup_manager: Arc<CgroupManager>) -> CpufreqBackend {
+        let backend_name = "cpufreq".to_string();
+
+        CpufreqBackend {
+            backend_name,
+            cgroup_manager,
+        }
+    }
+
+    fn make_metric_values(&self, job_id: i32, job_metrics: Vec<Metric>, cpus: &[u32]) -> Option<MetricValues> {
+        let frequencies: Vec<i64> = cpus.iter().filter_map(|cpu| get_cpu_frequency(*cpu)).collect();
+        if frequencies.is_empty() {
+            return None;
+        }
+        let mut metric_names: Vec<String> = Vec::new();
+        let mut metric_values: Vec<i64> = Vec::new();
+        for m in job_metrics {
+            let value = match m.metric_name.as_str() {
+                "cpufreq_min" => *frequencies.iter().min().unwrap(),
+                "cpufreq_mean" => frequencies.iter().sum::<i64>() / frequencies.len() as i64,
+                "cpufreq_max" => *frequencies.iter().max().unwrap(),
+                _ => continue,
+            };
+            metric_names.push(m.metric_name.clone());
+            metric_values.push(value);
+        }
+        Some(MetricValues {
+            job_id,
+            backend_name: self.backend_name.clone(),
+            metric_names,
+            metric_values: metric_values.into_iter().map(MetricValue::Int).collect(),
+        })
+    }
+}
+
+impl Backend for CpufreqBackend {
+    fn say_hello(&self) {
+        println!("hello my name is cpufreq backend");
+    }
+
+    fn get_backend_name(&self) -> String {
+        self.backend_name.clone()
+    }
+
+    // values of a job are computed over the cpus of its cpuset, node-wide values over all online cpus are associated to the fictive job 0
+    fn return_values(&self, metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues> {
+        let mut ret: HashMap<i32, MetricValues> = HashMap::new();
+        let cgroups = self.cgroup_manager.get_cgroups();
+        debug!("cgroup: {:#?}", cgroups);
+
+        for (cgroup_id, cgroup_name) in cgroups {
+            let job_metrics = get_job_metrics(&metrics_to_get, cgroup_id);
+            if job_metrics.is_empty() {
+                continue;
+            }
+            let cpus = self.cgroup_manager.get_cpuset_cpus(&cgroup_name);
+            if let Some(metric) = self.make_metric_values(cgroup_id, job_metrics, &cpus) {
+                ret.insert(cgroup_id, metric);
+            }
+        }
+
+        let node_metrics = get_job_metrics(&metrics_to_get, 0);
+        if !node_metrics.is_empty() {
+            let cpus = fs::read_to_string("/sys/devices/system/cpu/online")
+                .map(|content| parse_cpu_list(&content))
+                .unwrap_or_default();
+            if let Some(metric) = self.make_metric_values(0, node_metrics, &cpus) {
+                ret.insert(0, metric);
+            }
+        }
+        ret
+    }
+}
+
+// current frequency of a cpu in kHz, None if the cpu has no cpufreq driver
+fn get_cpu_frequency(cpu: u32) -> Option<i64> {
+    let filename = format!("/sys/devices/system/cpu/cpu{}/cpufreq/scaling_cur_freq", cpu);
+    fs::read_to_string(filename).ok().and_then(|c| c.trim().parse::<i64>().ok())
+}
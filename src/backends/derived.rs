@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fs;
+
+use yaml_rust::YamlLoader;
+
+// metrics computed on the node from the values of other metrics of the same job, defined in a yaml file:
+// derived_metrics:
+//   ipc_percent: 100 * instructions / cpu_cycles
+//   memory_usage_percent: 100 * rss / hierarchical_memory_limit
+//   user_ipc: '"instructions:u" / "cycles:u"'
+// expressions are computed as floating point numbers, metric names with other characters than letters, digits, '_', '@' and '.'
+// (pmu events such as cpu/mem-loads/, instances such as nfs_read_rtt@/home) are written between double quotes
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Metric(String),
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub struct DerivedMetric {
+    pub name: String,
+    expr: Expr,
+    // metrics used in the expression
+    pub operands: Vec<String>,
+}
+
+impl DerivedMetric {
+    pub fn new(name: &str, expression: &str) -> Result<DerivedMetric, String> {
+        let tokens = tokenize(expression)?;
+        let mut pos = 0;
+        let expr = parse_sum(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(format!("unexpected {:?} in {}", tokens[pos], expression));
+        }
+        let mut operands = Vec::new();
+        expr.metrics(&mut operands);
+        Ok(DerivedMetric { name: name.to_string(), expr, operands })
+    }
+
+    // None if a metric of the expression has no value or if the result is not a number (division by zero...)
+    pub fn compute(&self, values: &HashMap<String, f64>) -> Option<f64> {
+        let v = self.expr.eval(values)?;
+        if v.is_finite() { Some(v) } else { None }
+    }
+}
+
+impl Expr {
+    fn eval(&self, values: &HashMap<String, f64>) -> Option<f64> {
+        match self {
+            Expr::Number(n) => Some(*n),
+            Expr::Metric(name) => values.get(name).copied(),
+            Expr::Binary(left, op, right) => {
+                let (l, r) = (left.eval(values)?, right.eval(values)?);
+                match op {
+                    '+' => Some(l + r),
+                    '-' => Some(l - r),
+                    '*' => Some(l * r),
+                    _ => if r == 0. { None } else { Some(l / r) },
+                }
+            }
+        }
+    }
+
+    fn metrics(&self, res: &mut Vec<String>) {
+        match self {
+            Expr::Number(_) => (),
+            Expr::Metric(name) => if !res.contains(name) { res.push(name.clone()) },
+            Expr::Binary(left, _, right) => {
+                left.metrics(res);
+                right.metrics(res);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Op(char),
+    Open,
+    Close,
+}
+
+// metric names may contain an instance (metric_name@instance), other names are quoted
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '@' || c == '.'
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if "+-*/".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            chars.next();
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut word = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => word.push(c),
+                    None => return Err(format!("unterminated quoted name in {}", expression)),
+                }
+            }
+            if word.is_empty() {
+                return Err(format!("empty quoted name in {}", expression));
+            }
+            tokens.push(Token::Name(word));
+        } else if is_name_char(c) {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !is_name_char(c) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            match word.parse::<f64>() {
+                Ok(n) if word.starts_with(|c: char| c.is_ascii_digit()) => tokens.push(Token::Number(n)),
+                _ => tokens.push(Token::Name(word)),
+            }
+        } else {
+            return Err(format!("unexpected character {:?} in {}", c, expression));
+        }
+    }
+    Ok(tokens)
+}
+
+// sum := product (('+' | '-') product)*
+fn parse_sum(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let mut expr = parse_product(tokens, pos)?;
+    while let Some(Token::Op(op)) = tokens.get(*pos) {
+        if *op != '+' && *op != '-' {
+            break;
+        }
+        *pos += 1;
+        expr = Expr::Binary(Box::new(expr), *op, Box::new(parse_product(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+// product := operand (('*' | '/') operand)*
+fn parse_product(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let mut expr = parse_operand(tokens, pos)?;
+    while let Some(Token::Op(op)) = tokens.get(*pos) {
+        if *op != '*' && *op != '/' {
+            break;
+        }
+        *pos += 1;
+        expr = Expr::Binary(Box::new(expr), *op, Box::new(parse_operand(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+// operand := number | metric_name | '(' sum ')' | '-' operand
+fn parse_operand(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let token = tokens.get(*pos).cloned();
+    *pos += 1;
+    match token {
+        Some(Token::Number(n)) => Ok(Expr::Number(n)),
+        Some(Token::Name(name)) => Ok(Expr::Metric(name)),
+        Some(Token::Op('-')) => Ok(Expr::Binary(Box::new(Expr::Number(0.)), '-', Box::new(parse_operand(tokens, pos)?))),
+        Some(Token::Open) => {
+            let expr = parse_sum(tokens, pos)?;
+            if tokens.get(*pos) != Some(&Token::Close) {
+                return Err("missing )".to_string());
+            }
+            *pos += 1;
+            Ok(expr)
+        }
+        Some(t) => Err(format!("unexpected {:?}", t)),
+        None => Err("unexpected end of expression".to_string()),
+    }
+}
+
+// reads the derived metrics of the file, in the order of the file
+pub fn load_derived_metrics(filename: &str) -> Result<Vec<DerivedMetric>, String> {
+    let content = fs::read_to_string(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+    let doc = YamlLoader::load_from_str(&content)
+        .map_err(|e| format!("cannot parse {}: {}", filename, e))?
+        .pop()
+        .ok_or(format!("{} is empty", filename))?;
+    let definitions = doc["derived_metrics"].as_hash().ok_or(format!("no derived_metrics in {}", filename))?;
+    let mut res = Vec::new();
+    for (name, expression) in definitions {
+        let (name, expression) = match (name.as_str(), expression.as_str()) {
+            (Some(name), Some(expression)) => (name, expression),
+            _ => return Err(format!("derived metric {:?} of {} is not a name with an expression", name, filename)),
+        };
+        res.push(DerivedMetric::new(name, expression).map_err(|e| format!("derived metric {}: {}", name, e))?);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compute(expression: &str, values: &[(&str, f64)]) -> Option<f64> {
+        let values: HashMap<String, f64> = values.iter().map(|(n, v)| (n.to_string(), *v)).collect();
+        DerivedMetric::new("test", expression).unwrap().compute(&values)
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(compute("1 + 2 * 3", &[]), Some(7.));
+        assert_eq!(compute("2 * 3 + 1", &[]), Some(7.));
+        assert_eq!(compute("8 - 4 - 2", &[]), Some(2.));
+        assert_eq!(compute("8 / 4 / 2", &[]), Some(1.));
+        assert_eq!(compute("1 - 6 / 3 * 2", &[]), Some(-3.));
+    }
+
+    #[test]
+    fn test_parentheses() {
+        assert_eq!(compute("(1 + 2) * 3", &[]), Some(9.));
+        assert_eq!(compute("8 - (4 - 2)", &[]), Some(6.));
+        assert_eq!(compute("((2))", &[]), Some(2.));
+        assert!(DerivedMetric::new("test", "(1 + 2").is_err());
+        assert!(DerivedMetric::new("test", "()").is_err());
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        assert_eq!(compute("-2 * 3", &[]), Some(-6.));
+        assert_eq!(compute("1 - -2", &[]), Some(3.));
+        assert_eq!(compute("-(1 + 2)", &[]), Some(-3.));
+        assert_eq!(compute("-rss", &[("rss", 4.)]), Some(-4.));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        assert_eq!(compute("1 / 0", &[]), None);
+        assert_eq!(compute("rss / limit", &[("rss", 1.), ("limit", 0.)]), None);
+        assert_eq!(compute("0 * (1 / 0)", &[]), None);
+    }
+
+    #[test]
+    fn test_names() {
+        let metric = DerivedMetric::new("test", "rx_bytes@eth0 + rss.max * 2").unwrap();
+        assert_eq!(metric.operands, vec!["rx_bytes@eth0".to_string(), "rss.max".to_string()]);
+        assert_eq!(compute("rx_bytes@eth0 + rss.max * 2", &[("rx_bytes@eth0", 1.), ("rss.max", 3.)]), Some(7.));
+        assert_eq!(compute("1.5 * r01c2", &[("r01c2", 2.)]), Some(3.));
+        // an operand used twice is listed once
+        assert_eq!(DerivedMetric::new("test", "rss / (rss + cache)").unwrap().operands, vec!["rss".to_string(), "cache".to_string()]);
+    }
+
+    #[test]
+    fn test_quoted_names() {
+        let metric = DerivedMetric::new("test", "\"instructions:u\" / \"cpu/mem-loads/\" + \"nfs_read_rtt@/home\"").unwrap();
+        assert_eq!(metric.operands, vec!["instructions:u".to_string(), "cpu/mem-loads/".to_string(), "nfs_read_rtt@/home".to_string()]);
+        let values = [("instructions:u", 6.), ("cpu/mem-loads/", 3.), ("nfs_read_rtt@/home", 1.)];
+        assert_eq!(compute("\"instructions:u\"/\"cpu/mem-loads/\" + \"nfs_read_rtt@/home\"", &values), Some(3.));
+        // a quoted name is the same metric as the unquoted one, and is never a number
+        assert_eq!(compute("\"rss\" + rss", &[("rss", 2.)]), Some(4.));
+        assert_eq!(compute("\"1\" * 2", &[("1", 5.)]), Some(10.));
+        for expression in ["\"instructions:u", "\"\" + 1", "\"rss\"\"cache\"", "instructions:u"] {
+            assert!(DerivedMetric::new("test", expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_unknown_metric() {
+        assert_eq!(compute("rss / unknown", &[("rss", 1.)]), None);
+        assert_eq!(compute("rss + 1", &[]), None);
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        for expression in ["rss cache", "1 2", "rss )", "1 +", "", "* 2", "rss % 2", "rss + $x"] {
+            assert!(DerivedMetric::new("test", expression).is_err(), "{}", expression);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::backends::metric::instance_metric_name;
+use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
+use crate::backends::metric::MetricValues;
+use crate::backends::{get_job_metrics, Backend};
+use crate::utils::NameFilter;
+
+// fields of a line of /proc/diskstats after major, minor and device name (see Documentation/admin-guide/iostats.rst)
+// times are in milliseconds, discard fields appear with kernel 4.18 and flush fields with kernel 5.5
+const DISKSTATS_FIELDS: [&str; 17] = [
+    "reads_completed", "reads_merged", "sectors_read", "time_reading",
+    "writes_completed", "writes_merged", "sectors_written", "time_writing",
+    "ios_in_progress", "time_doing_ios", "weighted_time_doing_ios",
+    "discards_completed", "discards_merged", "sectors_discarded", "time_discarding",
+    "flushes_completed", "time_flushing",
+];
+
+pub struct DiskBackend {
+    pub backend_name: String,
+    devices_filter: NameFilter,
+    include_partitions: bool,
+}
+
+impl DiskBackend {
+    pub fn new(include: &str, exclude: &str, include_partitions: bool) -> DiskBackend {
+        let backend_name = "disk".to_string();
+        let devices_filter = NameFilter::new(include, exclude);
+
+        DiskBackend {
+            backend_name,
+            devices_filter,
+            include_partitions,
+        }
+    }
+
+    fn is_monitored(&self, device: &str) -> bool {
+        self.devices_filter.is_selected(device) && (self.include_partitions || !is_partition(device))
+    }
+}
+
+impl Backend for DiskBackend {
+    fn say_hello(&self) {
+        println!("hello my name is disk backend");
+    }
+
+    fn get_backend_name(&self) -> String {
+        self.backend_name.clone()
+    }
+
+    // disk statistics are node-wide, they are associated to the fictive job 0
+    fn return_values(&self, metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues> {
+        let mut ret: HashMap<i32, MetricValues> = HashMap::new();
+        let node_metrics = get_job_metrics(&metrics_to_get, 0);
+        if node_metrics.is_empty() {
+            return ret;
+        }
+
+        let devices: Vec<(String, HashMap<String, i64>)> = read_diskstats()
+            .into_iter()
+            .filter(|(device, _)| self.is_monitored(device))
+            .collect();
+
+        let mut metric_names: Vec<String> = Vec::new();
+        let mut metric_values: Vec<i64> = Vec::new();
+        for m in node_metrics {
+            for (device, values) in &devices {
+                if let Some(v) = values.get(&m.metric_name) {
+                    metric_names.push(instance_metric_name(&m.metric_name, device));
+                    metric_values.push(*v);
+                }
+            }
+        }
+
+        let metric = MetricValues {
+            job_id: 0,
+            backend_name: self.backend_name.clone(),
+            metric_names,
+            metric_values: metric_values.into_iter().map(MetricValue::Int).collect(),
+        };
+        ret.insert(0, metric);
+        ret
+    }
+}
+
+// partitions have a "partition" file in their sysfs directory
+fn is_partition(device: &str) -> bool {
+    // device mapper names may contain '/' in /proc/diskstats but never in sysfs
+    Path::new(&format!("/sys/class/block/{}/partition", device.replace('/', "!"))).exists()
+}
+
+// returns the list of block devices of /proc/diskstats with their counters, in the order of the file
+fn read_diskstats() -> Vec<(String, HashMap<String, i64>)> {
+    match fs::read_to_string("/proc/diskstats") {
+        Ok(content) => parse_diskstats(&content),
+        Err(e) => {
+            debug!("cannot read /proc/diskstats: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+fn parse_diskstats(content: &str) -> Vec<(String, HashMap<String, i64>)> {
+    let mut res = Vec::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        let mut h: HashMap<String, i64> = HashMap::new();
+        for (name, value) in DISKSTATS_FIELDS.iter().zip(fields[3..].iter()) {
+            if let Ok(v) = value.parse::<i64>() {
+                h.insert(name.to_string(), v);
+            }
+        }
+        res.push((fields[2].to_string(), h));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISKSTATS: &str = "\
+   8       0 sda 224683 70522 12641874 86361 418720 293104 21426456 392843 0 425764 521208 0 0 0 0 13452 42003
+   8       1 sda1 224540 70522 12635618 86316 418720 293104 21426456 392843 0 425748 479160 0 0 0 0 0 0
+ 253       0 dm-0 1520 0 34120 812 3 0 24 2 0 836 814
+   7       0 loop0
+   7       1 loop1 12 x 58
+";
+
+    #[test]
+    fn test_parse_diskstats() {
+        let res = parse_diskstats(DISKSTATS);
+        assert_eq!(res.iter().map(|(d, _)| d.as_str()).collect::<Vec<_>>(), vec!["sda", "sda1", "dm-0", "loop1"]);
+        let sda = &res[0].1;
+        assert_eq!(sda.len(), 17);
+        assert_eq!(sda["reads_completed"], 224683);
+        assert_eq!(sda["sectors_written"], 21426456);
+        assert_eq!(sda["flushes_completed"], 13452);
+        assert_eq!(sda["time_flushing"], 42003);
+        // kernels older than 4.18 have no discard nor flush counters
+        let dm0 = &res[2].1;
+        assert_eq!(dm0.len(), 11);
+        assert_eq!(dm0["weighted_time_doing_ios"], 814);
+        assert_eq!(dm0.get("discards_completed"), None);
+        // a field that is not a number is missing
+        let loop1 = &res[3].1;
+        assert_eq!((loop1["reads_completed"], loop1.get("reads_merged"), loop1["sectors_read"]), (12, None, 58));
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::backends::metric::instance_metric_name;
+use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
+use crate::backends::metric::MetricValues;
+use crate::backends::{get_job_metrics, Backend};
+
+const EDAC_PATH: &str = "/sys/devices/system/edac/mc";
+const CPU_PATH: &str = "/sys/devices/system/cpu";
+
+// counters of a memory controller (mc<N>/<file>)
+const EDAC_MC_FILES: [&str; 4] = ["ce_count", "ue_count", "ce_noinfo_count", "ue_noinfo_count"];
+// counters of the thermal_throttle directory of a cpu
+const THERMAL_THROTTLE_FILES: [&str; 4] = [
+    "core_throttle_count", "core_throttle_total_time_ms", "package_throttle_count", "package_throttle_total_time_ms",
+];
+
+pub struct HealthBackend {
+    pub backend_name: String,
+}
+
+impl HealthBackend {
+    pub fn new() -> HealthBackend {
+        let backend_name = "health".to_string();
+
+        HealthBackend { backend_name }
+    }
+}
+
+impl Backend for HealthBackend {
+    fn say_hello(&self) {
+        println!("hello my name is health backend");
+    }
+
+    fn get_backend_name(&self) -> String {
+        self.backend_name.clone()
+    }
+
+    // hardware health is node-wide, it is associated to the fictive job 0
+    fn return_values(&self, metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues> {
+        let mut ret: HashMap<i32, MetricValues> = HashMap::new();
+        let node_metrics = get_job_metrics(&metrics_to_get, 0);
+        if node_metrics.is_empty() {
+            return ret;
+        }
+
+        // metric_name -> list of (instance, value)
+        let mut h: HashMap<String, Vec<(String, i64)>> = HashMap::new();
+        read_edac_counters(&mut h, EDAC_PATH);
+        read_thermal_throttle_counters(&mut h, CPU_PATH);
+
+        let mut metric_names: Vec<String> = Vec::new();
+        let mut metric_values: Vec<i64> = Vec::new();
+        for m in node_metrics {
+            if let Some(values) = h.get(&m.metric_name) {
+                for (instance, value) in values {
+                    metric_names.push(instance_metric_name(&m.metric_name, instance));
+                    metric_values.push(*value);
+                }
+            }
+        }
+
+        let metric = MetricValues {
+            job_id: 0,
+            backend_name: self.backend_name.clone(),
+            metric_names,
+            metric_values: metric_values.into_iter().map(MetricValue::Int).collect(),
+        };
+        ret.insert(0, metric);
+        ret
+    }
+}
+
+// sorted names of the entries of a directory starting with prefix (ex: mc0, mc1...)
+fn list_dir(dirname: &str, prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(dirname) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with(prefix))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+fn read_counter(filename: &str) -> Option<i64> {
+    fs::read_to_string(filename).ok().and_then(|content| parse_counter(&content))
+}
+
+fn parse_counter(content: &str) -> Option<i64> {
+    content.trim().parse::<i64>().ok()
+}
+
+fn push_counter(h: &mut HashMap<String, Vec<(String, i64)>>, metric_name: &str, instance: &str, filename: &str) {
+    if let Some(v) = read_counter(filename) {
+        h.entry(metric_name.to_string()).or_default().push((instance.to_string(), v));
+    }
+}
+
+// corrected (ce) and uncorrected (ue) memory errors per memory controller (instance mc<N>)
+// and per DIMM row (instance mc<N>/csrow<M>) or per DIMM (instance mc<N>/dimm<M>) depending on the EDAC driver
+fn read_edac_counters(h: &mut HashMap<String, Vec<(String, i64)>>, edac_path: &str) {
+    for mc in list_dir(edac_path, "mc") {
+        let mc_path = format!("{}/{}", edac_path, mc);
+        for file in EDAC_MC_FILES.iter() {
+            push_counter(h, &format!("edac_{}", file), &mc, &format!("{}/{}", mc_path, file));
+        }
+        for csrow in list_dir(&mc_path, "csrow") {
+            let instance = format!("{}/{}", mc, csrow);
+            push_counter(h, "edac_ce_count", &instance, &format!("{}/{}/ce_count", mc_path, csrow));
+            push_counter(h, "edac_ue_count", &instance, &format!("{}/{}/ue_count", mc_path, csrow));
+        }
+        for dimm in list_dir(&mc_path, "dimm") {
+            let instance = format!("{}/{}", mc, dimm);
+            push_counter(h, "edac_ce_count", &instance, &format!("{}/{}/dimm_ce_count", mc_path, dimm));
+            push_counter(h, "edac_ue_count", &instance, &format!("{}/{}/dimm_ue_count", mc_path, dimm));
+        }
+    }
+}
+
+// thermal throttling events per cpu (instance cpu<N>), package counters are the same for all the cpus of a package
+fn read_thermal_throttle_counters(h: &mut HashMap<String, Vec<(String, i64)>>, cpu_path: &str) {
+    for cpu in list_dir(cpu_path, "cpu") {
+        if cpu.len() == 3 || !cpu[3..].chars().all(|c| c.is_ascii_digit()) {
+            continue; // cpufreq, cpuidle...
+        }
+        for file in THERMAL_THROTTLE_FILES.iter() {
+            push_counter(h, file, &cpu, &format!("{}/{}/thermal_throttle/{}", cpu_path, cpu, file));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // creates the files of a fake sysfs tree, given as (relative path, contents)
+    fn sysfs(files: &[(&str, &str)]) -> tempdir::TempDir {
+        let dir = tempdir::TempDir::new("colmet-health").unwrap();
+        for (path, content) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn counters(values: &[(&str, i64)]) -> Vec<(String, i64)> {
+        values.iter().map(|(instance, v)| (instance.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn test_parse_counter() {
+        assert_eq!(parse_counter("42\n"), Some(42));
+        assert_eq!(parse_counter("0"), Some(0));
+        assert_eq!(parse_counter(""), None);
+        assert_eq!(parse_counter("N/A\n"), None);
+    }
+
+    #[test]
+    fn test_read_edac_counters() {
+        let dir = sysfs(&[
+            ("mc0/ce_count", "3\n"),
+            ("mc0/ue_count", "0\n"),
+            ("mc0/ce_noinfo_count", "1\n"),
+            ("mc0/csrow0/ce_count", "2\n"),
+            ("mc0/csrow0/ue_count", "0\n"),
+            ("mc0/csrow1/ce_count", "1\n"),
+            ("mc1/ce_count", "\n"),
+            ("mc1/ue_count", "5\n"),
+            ("mc1/dimm0/dimm_ce_count", "7\n"),
+            ("mc1/dimm0/dimm_ue_count", "5\n"),
+            ("power/control", "auto\n"),
+        ]);
+        let mut h = HashMap::new();
+        read_edac_counters(&mut h, dir.path().to_str().unwrap());
+        assert_eq!(h["edac_ce_count"], counters(&[("mc0", 3), ("mc0/csrow0", 2), ("mc0/csrow1", 1), ("mc1/dimm0", 7)]));
+        assert_eq!(h["edac_ue_count"], counters(&[("mc0", 0), ("mc0/csrow0", 0), ("mc1", 5), ("mc1/dimm0", 5)]));
+        assert_eq!(h["edac_ce_noinfo_count"], counters(&[("mc0", 1)]));
+        // missing files have no value
+        assert!(!h.contains_key("edac_ue_noinfo_count"));
+    }
+
+    #[test]
+    fn test_read_thermal_throttle_counters() {
+        let dir = sysfs(&[
+            ("cpu0/thermal_throttle/core_throttle_count", "4\n"),
+            ("cpu0/thermal_throttle/package_throttle_count", "9\n"),
+            ("cpu12/thermal_throttle/core_throttle_count", "1\n"),
+            ("cpu12/thermal_throttle/core_throttle_total_time_ms", "250\n"),
+            ("cpufreq/thermal_throttle/core_throttle_count", "8\n"),
+            ("cpuidle/thermal_throttle/core_throttle_count", "8\n"),
+        ]);
+        let mut h = HashMap::new();
+        read_thermal_throttle_counters(&mut h, dir.path().to_str().unwrap());
+        assert_eq!(h["core_throttle_count"], counters(&[("cpu0", 4), ("cpu12", 1)]));
+        assert_eq!(h["core_throttle_total_time_ms"], counters(&[("cpu12", 250)]));
+        assert_eq!(h["package_throttle_count"], counters(&[("cpu0", 9)]));
+        assert!(!h.contains_key("package_throttle_total_time_ms"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::sync::Arc;
+
+use crate::backends::metric::instance_metric_name;
+use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
+use crate::backends::metric::MetricValues;
+use crate::backends::{get_job_metrics, Backend};
+use crate::cgroup_manager::CgroupManager;
+
+pub struct HugetlbBackend {
+    pub backend_name: String,
+    cgroup_manager: Arc<CgroupManager>,
+}
+
+impl HugetlbBackend {
+    pub fn new(cgroup_manager: Arc<CgroupManager>) -> HugetlbBackend {
+        let backend_name = "hugetlb".to_string();
+
+        HugetlbBackend {
+            backend_name,
+            cgroup_manager,
+        }
+    }
+}
+
+impl Backend for HugetlbBackend {
+    fn say_hello(&self) {
+        println!("hello my name is hugetlb backend");
+    }
+
+    fn get_backend_name(&self) -> String {
+        self.backend_name.clone()
+    }
+
+    fn return_values(&self, metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues> {
+        let mut ret: HashMap<i32, MetricValues> = HashMap::new();
+        let cgroups = self.cgroup_manager.get_cgroups();
+        debug!("cgroup: {:#?}", cgroups);
+
+        for (cgroup_id, cgroup_name) in cgroups {
+            let job_metrics = get_job_metrics(&metrics_to_get, cgroup_id);
+            if job_metrics.is_empty() {
+                continue;
+            }
+            let dirname = self.cgroup_manager.get_cgroup_path("hugetlb", &cgroup_name);
+            let (metric_names, metric_values) = get_metric_values(&dirname, job_metrics, self.cgroup_manager.cgroup_v2);
+            if metric_names.is_empty() {
+                continue;
+            }
+
+            let metric = MetricValues {
+                job_id: cgroup_id,
+                backend_name: self.backend_name.clone(),
+                metric_names,
+                metric_values: metric_values.into_iter().map(MetricValue::Int).collect(),
+            };
+            ret.insert(cgroup_id, metric);
+        }
+        ret
+    }
+}
+
+// cgroup v1 files are hugetlb.<size>.usage_in_bytes, hugetlb.<size>.max_usage_in_bytes and hugetlb.<size>.failcnt
+// cgroup v2 files are hugetlb.<size>.current and hugetlb.<size>.events (the "max" line counts allocation failures), there is no max usage
+// returns names metric_name@size and their values, for every requested metric and every page size found in the cgroup
+fn get_metric_values(dirname: &str, metrics_to_get: Vec<Metric>, cgroup_v2: bool) -> (Vec<String>, Vec<i64>) {
+    let mut names: Vec<String> = Vec::new();
+    let mut res: Vec<i64> = Vec::new();
+    let entries = match fs::read_dir(dirname) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("cannot read hugetlb cgroup {}: {}", dirname, e);
+            return (names, res);
+        }
+    };
+
+    let mut page_sizes: BTreeSet<String> = BTreeSet::new();
+    let mut h: HashMap<String, i64> = HashMap::new();
+    for entry in entries.flatten() {
+        let filename = entry.file_name().to_string_lossy().to_string();
+        if hugetlb_file_metric(&filename, cgroup_v2).is_none() {
+            continue;
+        }
+        let content = match fs::read_to_string(entry.path()) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        if let Some((metric_name, page_size, v)) = parse_hugetlb_file(&filename, &content, cgroup_v2) {
+            page_sizes.insert(page_size.to_string());
+            h.insert(instance_metric_name(metric_name, page_size), v);
+        }
+    }
+
+    for m in metrics_to_get {
+        for page_size in &page_sizes {
+            let name = instance_metric_name(&m.metric_name, page_size);
+            if let Some(v) = h.get(&name) {
+                names.push(name);
+                res.push(*v);
+            }
+        }
+    }
+    (names, res)
+}
+
+// metric of a file of the hugetlb cgroup and its page size, None for hugetlb.<size>.rsvd.* and the files of other controllers
+fn hugetlb_file_metric(filename: &str, cgroup_v2: bool) -> Option<(&'static str, &str)> {
+    let parts: Vec<&str> = filename.split('.').collect();
+    if parts.len() != 3 || parts[0] != "hugetlb" {
+        return None;
+    }
+    let metric_name = match (cgroup_v2, parts[2]) {
+        (false, "usage_in_bytes") => "hugetlb_usage_in_bytes",
+        (false, "max_usage_in_bytes") => "hugetlb_max_usage_in_bytes",
+        (false, "failcnt") => "hugetlb_failcnt",
+        (true, "current") => "hugetlb_usage_in_bytes",
+        (true, "events") => "hugetlb_failcnt",
+        _ => return None,
+    };
+    Some((metric_name, parts[1]))
+}
+
+// (metric_name, page size, value) of the content of a file of the hugetlb cgroup
+fn parse_hugetlb_file<'a>(filename: &'a str, content: &str, cgroup_v2: bool) -> Option<(&'static str, &'a str, i64)> {
+    let (metric_name, page_size) = hugetlb_file_metric(filename, cgroup_v2)?;
+    let value = if filename.ends_with(".events") { parse_events_max(content)? } else { content.trim().parse::<i64>().ok()? };
+    Some((metric_name, page_size, value))
+}
+
+fn parse_events_max(content: &str) -> Option<i64> {
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() == 2 && fields[0] == "max" {
+            return fields[1].parse::<i64>().ok();
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_events_max() {
+        assert_eq!(parse_events_max("max 3\n"), Some(3));
+        assert_eq!(parse_events_max("max 0\nother 5\n"), Some(0));
+        // the line may be missing, short, or have no number
+        assert_eq!(parse_events_max(""), None);
+        assert_eq!(parse_events_max("other 5\n"), None);
+        assert_eq!(parse_events_max("max\n"), None);
+        assert_eq!(parse_events_max("max max\n"), None);
+        assert_eq!(parse_events_max("max 1 2\n"), None);
+    }
+
+    #[test]
+    fn test_parse_hugetlb_file() {
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.usage_in_bytes", "4194304\n", false), Some(("hugetlb_usage_in_bytes", "2MB", 4194304)));
+        assert_eq!(parse_hugetlb_file("hugetlb.1GB.max_usage_in_bytes", "0\n", false), Some(("hugetlb_max_usage_in_bytes", "1GB", 0)));
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.failcnt", "7\n", false), Some(("hugetlb_failcnt", "2MB", 7)));
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.current", "2097152\n", true), Some(("hugetlb_usage_in_bytes", "2MB", 2097152)));
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.events", "max 2\n", true), Some(("hugetlb_failcnt", "2MB", 2)));
+        // the limit "max" of cgroup v2 is not a value
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.max", "max\n", true), None);
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.usage_in_bytes", "", false), None);
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.events", "", true), None);
+        // files of the other version, reservations and other controllers
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.current", "2097152\n", false), None);
+        assert_eq!(parse_hugetlb_file("hugetlb.2MB.rsvd.current", "0\n", true), None);
+        assert_eq!(parse_hugetlb_file("memory.current", "0\n", true), None);
+    }
+}
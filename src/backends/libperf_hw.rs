@@ -0,0 +1,201 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_int, c_longlong};
+
+use crate::utils::parse_cpu_list;
+
+// safe interface of libperf_hw, every failure of the library is turned into a PerfhwError
+
+#[link(name = "perf_hw", kind="static")]
+extern "C" {
+    fn init_cgroup(cgroup_name: *const c_char, metrics: *const c_char, cpus: *const c_int, nb_cpus: c_int, unknown_event: *mut c_int) -> c_int;
+    fn get_counters(values: *mut c_longlong, running_ratios: *mut f64, nb_values: c_int, cgroup_name: *const c_char) -> c_int;
+    fn get_counters_per_core(values: *mut c_longlong, running_ratios: *mut f64, nb_values: c_int, cgroup_name: *const c_char) -> c_int;
+    fn get_counter_errors(errors: *mut c_int, nb_values: c_int, cgroup_name: *const c_char) -> c_int;
+    fn get_nb_counters(cgroup_name: *const c_char) -> c_int;
+    fn remove_cgroup(cgroup_name: *const c_char);
+}
+
+#[derive(Debug)]
+pub enum PerfhwError {
+    // the perf_event cgroup does not exist, or its counters are not opened in the library
+    CgroupNotFound(String),
+    // perf_event_paranoid or missing capabilities, with the errno
+    PermissionDenied(i32),
+    // the cpu or the kernel cannot count the event, with the errno
+    EventUnsupported(i32),
+    // opening the counters failed for another reason, with the errno
+    Open(i32),
+    // reading the counters failed, with the errno
+    Read(i32),
+    // the library does not know the event
+    UnknownEvent(String),
+    // the library did not open the expected events
+    EventCount { expected: usize, found: usize },
+    InvalidName(String),
+}
+
+impl fmt::Display for PerfhwError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PerfhwError::CgroupNotFound(name) => write!(f, "perf_event cgroup {} not found", name),
+            PerfhwError::PermissionDenied(errno) => write!(f, "permission denied: {}", io::Error::from_raw_os_error(*errno)),
+            PerfhwError::EventUnsupported(errno) => write!(f, "event not supported: {}", io::Error::from_raw_os_error(*errno)),
+            PerfhwError::Open(errno) => write!(f, "cannot open counters: {}", io::Error::from_raw_os_error(*errno)),
+            PerfhwError::Read(errno) => write!(f, "cannot read counters: {}", io::Error::from_raw_os_error(*errno)),
+            PerfhwError::UnknownEvent(event) => write!(f, "unknown event {}", event),
+            PerfhwError::EventCount { expected, found } => write!(f, "{} events opened instead of {}", found, expected),
+            PerfhwError::InvalidName(name) => write!(f, "invalid name {:?}", name),
+        }
+    }
+}
+
+impl PerfhwError {
+    // error of perf_event_open
+    fn from_open_errno(errno: i32) -> PerfhwError {
+        match errno {
+            libc::EACCES | libc::EPERM => PerfhwError::PermissionDenied(errno),
+            _ => PerfhwError::EventUnsupported(errno),
+        }
+    }
+}
+
+fn to_cstring(s: &str) -> Result<CString, PerfhwError> {
+    CString::new(s).map_err(|_| PerfhwError::InvalidName(s.to_string()))
+}
+
+// opens the counters of the events (see perf_event_list in perf_hw.c for the syntax) for the cgroup, does nothing if they are already opened
+// returns true if the counters were opened by this call
+pub fn open_counters(cgroup_name: &str, events: &str) -> Result<bool, PerfhwError> {
+    let c_cgroup_name = to_cstring(cgroup_name)?;
+    let c_events = to_cstring(events)?;
+    let c_cpus: Vec<c_int> = cpus().iter().map(|cpu| *cpu as c_int).collect();
+    let mut unknown_event: c_int = -1;
+    let res = unsafe {init_cgroup(c_cgroup_name.as_ptr(), c_events.as_ptr(), c_cpus.as_ptr(), c_cpus.len() as c_int, &mut unknown_event)};
+    match res {
+        1 => Ok(false),
+        2 => Ok(true),
+        _ if unknown_event >= 0 => Err(PerfhwError::UnknownEvent(nth_event(events, unknown_event as usize))),
+        res if -res == libc::ENOENT => Err(PerfhwError::CgroupNotFound(cgroup_name.to_string())),
+        res if -res == libc::EACCES || -res == libc::EPERM => Err(PerfhwError::PermissionDenied(-res)),
+        res => Err(PerfhwError::Open(-res)),
+    }
+}
+
+// event at index in a list of events joined by ',' and '+', as counted by perf_event_list
+fn nth_event(events: &str, index: usize) -> String {
+    events.split([',', '+']).filter(|e| !e.is_empty()).nth(index).unwrap_or(events).to_string()
+}
+
+// error of each event of the cgroup, None if the event is counted on all the cores
+// an event that cannot be opened on a core, or whose group has such an event, is not counted at all
+pub fn counter_errors(cgroup_name: &str, nb_events: usize) -> Result<Vec<Option<PerfhwError>>, PerfhwError> {
+    let c_cgroup_name = to_cstring(cgroup_name)?;
+    let mut errors: Vec<c_int> = vec![0; nb_events];
+    let res = unsafe {get_counter_errors(errors.as_mut_ptr(), errors.len() as c_int, c_cgroup_name.as_ptr())};
+    check_nb_events(cgroup_name, res, nb_events)?;
+    Ok(errors.into_iter().map(|errno| if errno == 0 { None } else { Some(PerfhwError::from_open_errno(errno)) }).collect())
+}
+
+// value of each event summed over all cores and running ratio of each event, the counters are reset
+pub fn read_counters(cgroup_name: &str, nb_events: usize) -> Result<(Vec<i64>, Vec<f64>), PerfhwError> {
+    let c_cgroup_name = to_cstring(cgroup_name)?;
+    let mut values: Vec<c_longlong> = vec![0; nb_events];
+    let mut ratios: Vec<f64> = vec![1.0; nb_events];
+    let res = unsafe {get_counters(values.as_mut_ptr(), ratios.as_mut_ptr(), values.len() as c_int, c_cgroup_name.as_ptr())};
+    check_nb_events(cgroup_name, res, nb_events)?;
+    Ok((values, ratios))
+}
+
+// values of each event on each core (values[event][core], in the order of cpus()) and running ratio of each event, the counters are reset
+pub fn read_counters_per_core(cgroup_name: &str, nb_events: usize) -> Result<(Vec<Vec<i64>>, Vec<f64>), PerfhwError> {
+    let c_cgroup_name = to_cstring(cgroup_name)?;
+    let nb_cores = cpus().len();
+    let mut values: Vec<c_longlong> = vec![0; nb_events*nb_cores];
+    let mut ratios: Vec<f64> = vec![1.0; nb_events];
+    let res = unsafe {get_counters_per_core(values.as_mut_ptr(), ratios.as_mut_ptr(), values.len() as c_int, c_cgroup_name.as_ptr())};
+    check_nb_events(cgroup_name, res, nb_events)?;
+    Ok((values.chunks(nb_cores).map(|v| v.to_vec()).collect(), ratios))
+}
+
+// the functions of the library return the number of events of the cgroup, or -errno
+fn check_nb_events(cgroup_name: &str, res: c_int, expected: usize) -> Result<(), PerfhwError> {
+    if res == -libc::ENOENT {
+        return Err(PerfhwError::CgroupNotFound(cgroup_name.to_string()));
+    }
+    if res < 0 {
+        return Err(PerfhwError::Read(-res));
+    }
+    if res as usize != expected {
+        return Err(PerfhwError::EventCount { expected, found: res as usize });
+    }
+    Ok(())
+}
+
+// number of file descriptors opened for the cgroup, None if its counters are not opened
+pub fn nb_counters(cgroup_name: &str) -> Option<usize> {
+    let c_cgroup_name = to_cstring(cgroup_name).ok()?;
+    let res = unsafe {get_nb_counters(c_cgroup_name.as_ptr())};
+    if res < 0 { None } else { Some(res as usize) }
+}
+
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+lazy_static! {
+    static ref CPUS: Vec<u32> = online_cpus();
+}
+
+// ids of the cpus the counters are opened on, the online cpus may not be contiguous (ex 0-3,8,10-11)
+pub fn cpus() -> &'static [u32] {
+    &CPUS
+}
+
+fn online_cpus() -> Vec<u32> {
+    let cpus = match fs::read_to_string(ONLINE_CPUS) {
+        Ok(content) => parse_cpu_list(&content),
+        Err(e) => {
+            warn!("cannot read {}: {}", ONLINE_CPUS, e);
+            Vec::new()
+        }
+    };
+    if !cpus.is_empty() {
+        return cpus;
+    }
+    let nb_cpus = unsafe {libc::sysconf(libc::_SC_NPROCESSORS_ONLN)}.max(1) as u32;
+    (0..nb_cpus).collect()
+}
+
+// closes the counters of the cgroup
+pub fn close_counters(cgroup_name: &str) {
+    if let Ok(c_cgroup_name) = to_cstring(cgroup_name) {
+        unsafe {remove_cgroup(c_cgroup_name.as_ptr())};
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nth_event() {
+        assert_eq!(nth_event("instructions+cpu_cycles,cache_misses:u", 0), "instructions");
+        assert_eq!(nth_event("instructions+cpu_cycles,cache_misses:u", 2), "cache_misses:u");
+        assert_eq!(nth_event("instructions,,cpu_cycles", 1), "cpu_cycles");
+    }
+
+    #[test]
+    fn test_unknown_event() {
+        // events are parsed before the cgroup is opened
+        match open_counters("/nonexistent/colmet", "instructions+cpu_cycles,bogus:u,cache_misses") {
+            Err(PerfhwError::UnknownEvent(event)) => assert_eq!(event, "bogus:u"),
+            res => panic!("unexpected {:?}", res),
+        }
+        match open_counters("/nonexistent/colmet", "instructions,pmu:4:c0") {
+            Err(PerfhwError::UnknownEvent(event)) => assert_eq!(event, "pmu:4:c0"),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(matches!(open_counters("/nonexistent/colmet", "instructions:k"), Err(PerfhwError::CgroupNotFound(_))));
+    }
+}
@@ -1,11 +1,78 @@
 use serde::ser::{Serialize, Serializer, SerializeStruct};
 
+// metrics measured on several instances (page size, network interface, block device...) are named metric_name@instance
+pub const INSTANCE_SEPARATOR: char = '@';
+
+pub fn instance_metric_name(metric_name: &str, instance: &str) -> String {
+    format!("{}{}{}", metric_name, INSTANCE_SEPARATOR, instance)
+}
+
+// split metric_name@instance into (metric_name, Some(instance)), names without instance give (metric_name, None)
+pub fn split_metric_name(name: &str) -> (&str, Option<&str>) {
+    match name.split_once(INSTANCE_SEPARATOR) {
+        Some((metric_name, instance)) => (metric_name, Some(instance)),
+        None => (name, None),
+    }
+}
+
+// how the values of a metric are sent: as collected, as the difference with the previous sample or as this difference per second
+// delta and rate are meant for cumulative counters (pgfault, nr_throttled...)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricMode {
+    Raw,
+    Delta,
+    Rate,
+}
+
+// parses metric_name=mode,... (ex pgfault=rate,nr_throttled=delta), None if a mode is unknown
+pub fn parse_metric_modes(modes: &str) -> Option<Vec<(String, MetricMode)>> {
+    let mut res = Vec::new();
+    for m in modes.split(',').map(|m| m.trim()).filter(|m| !m.is_empty()) {
+        let (metric_name, mode) = m.split_once('=')?;
+        let mode = match mode.trim() {
+            "raw" => MetricMode::Raw,
+            "delta" => MetricMode::Delta,
+            "rate" => MetricMode::Rate,
+            _ => return None,
+        };
+        res.push((metric_name.trim().to_string(), mode));
+    }
+    Some(res)
+}
+
+// values are sent as msgpack integers or floats, metrics collected as integers stay integers on the wire
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricValue {
+    Int(i64),
+    Float(f64),
+}
+
+impl MetricValue {
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            MetricValue::Int(v) => *v as f64,
+            MetricValue::Float(v) => *v,
+        }
+    }
+}
+
+impl Serialize for MetricValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where
+        S:Serializer,
+    {
+        match self {
+            MetricValue::Int(v) => serializer.serialize_i64(*v),
+            MetricValue::Float(v) => serializer.serialize_f64(*v),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MetricValues {
     pub job_id: i32,
     pub backend_name: String,
     pub metric_names: Vec<String>,
-    pub metric_values: Vec<i64>,
+    pub metric_values: Vec<MetricValue>,
 }
 
 #[derive(Debug, Clone)]
@@ -1,12 +1,25 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::process::exit;
+use std::sync::{Arc, Mutex};
 
 use crate::backends::memory::MemoryBackend;
 use crate::backends::cpu::CpuBackend;
+use crate::backends::cpufreq::CpufreqBackend;
+use crate::backends::aggregation::{parse_metric_aggregations, Aggregation, WindowStats};
+use crate::backends::alert::AlertEngine;
+use crate::backends::derived::{load_derived_metrics, DerivedMetric};
+use crate::backends::disk::DiskBackend;
+use crate::backends::health::HealthBackend;
+use crate::backends::hugetlb::HugetlbBackend;
+use crate::backends::network::NetworkBackend;
+use crate::backends::nfs::NfsBackend;
 
 use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
 use crate::backends::metric::MetricValues;
+use crate::backends::metric::{instance_metric_name, parse_metric_modes, split_metric_name, MetricMode};
 use crate::cgroup_manager::CgroupManager;
+use crate::event::Event;
 use crate::CliArgs;
 
 use crate::backends::perfhw::PerfhwBackend;
@@ -17,9 +30,20 @@ use yaml_rust::YamlLoader;
 
 pub(crate) mod metric;
 
+mod aggregation;
+mod alert;
 mod memory;
 mod cpu;
+mod cpufreq;
+mod derived;
+mod disk;
+mod health;
+mod hugetlb;
+mod libperf_hw;
+mod network;
+mod nfs;
 mod perfhw;
+mod pmu;
 
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -35,18 +59,40 @@ fn load_metrics_from_yaml() -> yaml_rust::Yaml {
         .unwrap()
 }
 
-// HashMap("metric_name", (id, "backend_name")))
+// description of a metric in metrics_order.yml
+#[derive(Debug, Clone, Default)]
+pub struct MetricInfo {
+    // gauge, counter or delta
+    pub kind: String,
+    pub unit: String,
+    pub description: String,
+}
+
+// a metric of metrics_order.yml is either a name or a name with its description (- cache: {kind: gauge, unit: bytes, description: ...})
+fn parse_metric_entry(metric: &yaml_rust::Yaml) -> (String, MetricInfo) {
+    if let Some(name) = metric.as_str() {
+        return (name.to_string(), MetricInfo::default());
+    }
+    let (name, info) = metric.as_hash().and_then(|h| h.iter().next()).expect("invalid metric in metrics_order.yml");
+    let field = |key: &str| info[key].as_str().unwrap_or("").to_string();
+    (name.as_str().unwrap().to_string(), MetricInfo { kind: field("kind"), unit: field("unit"), description: field("description") })
+}
+
+// HashMap("metric_name", (id, "backend_name", info)))
 // used to send shorter messages on the network, metric names are replaced by an id, this list must be the same in colmet-collector
 // also used when adding metrics to get to find which backend can handle the requested metric
 lazy_static! {  
-    static ref METRIC_NAMES_MAP: HashMap<String, (i32, String)> = {
+    static ref METRIC_NAMES_MAP: HashMap<String, (i32, String, MetricInfo)> = {
         let mut m = HashMap::new();
         let doc = load_metrics_from_yaml();
         let mut i = 0;
         for back in doc["metrics_order"].as_hash().unwrap() {
             let (b, array)=back;
             for metric in array.as_vec().unwrap() { 
-                m.insert(metric.as_str().unwrap().to_string(), (i, b.as_str().unwrap().to_string()));
+                let (name, info) = parse_metric_entry(metric);
+                // sections named backend/part hold metrics of backend appended after the other sections, to keep the ids
+                let backend_name = b.as_str().unwrap().split('/').next().unwrap().to_string();
+                m.insert(name, (i, backend_name, info));
                 i += 1;
             }
         }
@@ -54,24 +100,104 @@ lazy_static! {
     };
     static ref METRICS_VERSION: i64 = load_metrics_from_yaml()["meta"]["version"].as_i64().unwrap() ;
     static ref NB_METRICS: usize = METRIC_NAMES_MAP.len();
+    // HashMap("metric_name", id) of the metrics that are not in metrics_order.yml (perfhw pmu and raw events)
+    static ref DYNAMIC_METRIC_IDS: Mutex<HashMap<String, i32>> = Mutex::new(HashMap::new());
+}
+
+// ids of the metrics that are not in metrics_order.yml start here, they are sent to colmet-collector in a dynamic_metrics event
+const FIRST_DYNAMIC_METRIC_ID: i32 = 65536;
+
+// id of a metric, metrics that are not in metrics_order.yml get a new id the first time they are seen
+fn get_metric_id(metric_name: &str) -> i32 {
+    if let Some((id, _, _)) = METRIC_NAMES_MAP.get(metric_name) {
+        return *id;
+    }
+    let mut dynamic_ids = DYNAMIC_METRIC_IDS.lock().unwrap();
+    let next_id = FIRST_DYNAMIC_METRIC_ID + dynamic_ids.len() as i32;
+    *dynamic_ids.entry(metric_name.to_string()).or_insert(next_id)
+}
+
+// name of the backend that can handle the metric, None if the metric is unknown
+fn get_metric_backend(metric_name: &str) -> Option<String> {
+    if let Some((_, backend_name, _)) = METRIC_NAMES_MAP.get(metric_name) {
+        return Some(backend_name.clone());
+    }
+    // perfhw events with a mode modifier (ex instructions:u) are distinct metrics
+    let (event, modifier) = pmu::split_modifier(metric_name);
+    if modifier.is_some() && event != perfhw::RUNNING_PERCENT && get_metric_backend(event).as_deref() == Some("perfhw") {
+        return Some("perfhw".to_string());
+    }
+    if pmu::is_dynamic_event(metric_name) {
+        return Some("perfhw".to_string());
+    }
+    None
+}
+
+// catalogue of metrics_order.yml, one line per metric in the order of the ids: id, backend, name, kind, unit and description separated by tabs
+pub fn list_metrics() -> String {
+    let mut metrics: Vec<(&String, &(i32, String, MetricInfo))> = METRIC_NAMES_MAP.iter().collect();
+    metrics.sort_by_key(|(_, (id, _, _))| *id);
+    let mut res = format!("# metrics_order.yml version {}\n", *METRICS_VERSION);
+    for (name, (id, backend_name, info)) in metrics {
+        res += &format!("{}\t{}\t{}\t{}\t{}\t{}\n", id, backend_name, name, info.kind, info.unit, info.description);
+    }
+    res
+}
 
+// event describing the metrics of metrics_order.yml, sent once to colmet-collector when colmet starts
+// details are "version" and "<metric_name>.<id|backend|kind|unit|description>"
+pub fn metrics_metadata_event(timestamp: i64, hostname: String) -> Event {
+    let mut details = HashMap::new();
+    details.insert("version".to_string(), METRICS_VERSION.to_string());
+    for (name, (id, backend_name, info)) in METRIC_NAMES_MAP.iter() {
+        details.insert(format!("{}.id", name), id.to_string());
+        details.insert(format!("{}.backend", name), backend_name.clone());
+        details.insert(format!("{}.kind", name), info.kind.clone());
+        details.insert(format!("{}.unit", name), info.unit.clone());
+        details.insert(format!("{}.description", name), info.description.clone());
+    }
+    Event {
+        event_type: "metrics_metadata".to_string(),
+        hostname,
+        timestamp,
+        job_id: -1,
+        details,
+    }
 }
 
-// replace metric names by their id
+// replace metric names by their id, the instance of metric_name@instance is kept (id@instance)
 pub fn compress_metric_names(metric_names: Vec<String>) -> Vec<String> {
     debug!("compress_metric_names");
     let mut res: Vec<String> = Vec::new();
     for metric_name in metric_names {
         // debug!("compress_metric_names metric_name {:#?}", metric_name.as_str().clone());
-        res.push(format!("{}", METRIC_NAMES_MAP.get(metric_name.as_str()).unwrap().0));
+        let (name, instance) = split_metric_name(&metric_name);
+        let id = get_metric_id(name);
+        match instance {
+            Some(instance) => res.push(instance_metric_name(&id.to_string(), instance)),
+            None => res.push(format!("{}", id)),
+        }
     }
     res
 }
 
+// metrics to collect for a job: the ones requested for this job and the ones requested for all jobs (job_id -1)
+pub fn get_job_metrics(metrics_to_get: &HashMap<i32, Vec<Metric>>, job_id: i32) -> Vec<Metric> {
+    let mut metrics = metrics_to_get.get(&job_id).cloned().unwrap_or_default();
+    if job_id != -1 {
+        if let Some(all_jobs) = metrics_to_get.get(&(-1)) {
+            metrics.extend(all_jobs.iter().cloned());
+        }
+    }
+    metrics
+}
+
 pub trait Backend {
     fn say_hello(&self); // for debug
     fn get_backend_name(&self) -> String;
     fn return_values(&self, metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues>;
+    // called when a job ends, to release the resources the backend holds for it
+    fn remove_job(&self, _job_id: i32, _cgroup_name: &str) {}
 }
 
 pub struct BackendsManager {
@@ -81,6 +207,28 @@ pub struct BackendsManager {
     pub sample_period: i64,
     pub last_measurement: HashMap<i32, (String, i64, i64, Vec<MetricValues>)>,
     pub metrics_modified: bool,
+    cgroup_manager: Option<Arc<CgroupManager>>,
+    nb_dynamic_metrics_sent: usize,
+    derived_metrics: Vec<DerivedMetric>,
+    // job_id -> metric_name -> last value collected (and derived), operands of the derived metrics and of the alert rules
+    latest_values: HashMap<i32, HashMap<String, f64>>,
+    // metric_name -> mode, metrics that are not in the map are sent as collected
+    metric_modes: HashMap<String, MetricMode>,
+    // job_id -> metric_name -> (value, timestamp) of the previous sample of the metrics sent as delta or rate
+    previous_samples: HashMap<i32, HashMap<String, (MetricValue, i64)>>,
+    // ids (id@instance) of the metrics sent as delta or rate, and job_id -> those of them that got a value in the current measure
+    mode_metric_ids: HashSet<String>,
+    collected_mode_metrics: HashMap<i32, HashSet<String>>,
+    // duration of the aggregation windows in milliseconds, 0 if the values are sent as they are collected
+    aggregation_window: i64,
+    window_start: i64,
+    // metric_name -> aggregations sent at the end of a window, metrics that are not in the map are sent as their last value
+    metric_aggregations: HashMap<String, Vec<Aggregation>>,
+    // job_id -> backend_name -> values of each metric (metric_name@instance) during the current window
+    windows: HashMap<i32, HashMap<String, Vec<(String, WindowStats)>>>,
+    alert_engine: Option<AlertEngine>,
+    // alerts that fired or were resolved since the last call to take_alert_events
+    alert_events: Vec<Event>,
 }
 
 impl BackendsManager {
@@ -93,21 +241,91 @@ impl BackendsManager {
         let sample_period=(sp*1000.)as i64;
         for m in metrics {
             let mut met=m.clone();
-            met.backend_name=METRIC_NAMES_MAP.get(&m.metric_name).unwrap().1.clone();
+            met.backend_name=get_metric_backend(&m.metric_name).unwrap();
+            get_metric_id(&m.metric_name);
             if met.sampling_period != -1.{
                 met.sampling_period=round_sampling(sample_period, met.sampling_period);
             }
             metrics_to_get.push(met);
         }
         debug!("{:?}", metrics_to_get);
-        BackendsManager { backends, metrics_to_get, last_timestamp, last_measurement, metrics_modified, sample_period }
+        let cgroup_manager = None;
+        let nb_dynamic_metrics_sent = 0;
+        let derived_metrics = Vec::new();
+        let latest_values = HashMap::new();
+        let metric_modes = HashMap::new();
+        let previous_samples = HashMap::new();
+        let mode_metric_ids = HashSet::new();
+        let collected_mode_metrics = HashMap::new();
+        let aggregation_window = 0;
+        let window_start = 0;
+        let metric_aggregations = HashMap::new();
+        let windows = HashMap::new();
+        let alert_engine = None;
+        let alert_events = Vec::new();
+        BackendsManager {
+            backends, metrics_to_get, last_timestamp, last_measurement, metrics_modified, sample_period, cgroup_manager,
+            nb_dynamic_metrics_sent, derived_metrics, latest_values, metric_modes, previous_samples,
+            mode_metric_ids, collected_mode_metrics,
+            aggregation_window, window_start, metric_aggregations, windows, alert_engine, alert_events
+        }
     }
 
-    pub fn init_backends(& self, cli_args: CliArgs, cgroup_manager : Arc<CgroupManager>){
+    pub fn init_backends(&mut self, cli_args: CliArgs, cgroup_manager : Arc<CgroupManager>){
+        self.cgroup_manager = Some(cgroup_manager.clone());
+        match parse_metric_modes(&cli_args.metric_modes) {
+            Some(modes) => self.metric_modes = modes.into_iter().collect(),
+            None => {
+                error!("invalid metric modes {:?}, correct format is 'metric_name=raw|delta|rate,...'", cli_args.metric_modes);
+                exit(1);
+            }
+        }
+        self.aggregation_window = (cli_args.aggregation_window * 1000.) as i64;
+        match parse_metric_aggregations(&cli_args.metric_aggregations) {
+            Some(aggregations) => self.metric_aggregations = aggregations.into_iter().collect(),
+            None => {
+                error!("invalid metric aggregations {:?}, correct format is 'metric_name=min+max+mean+last+sum,...'", cli_args.metric_aggregations);
+                exit(1);
+            }
+        }
+        if self.aggregation_window > 0 && self.aggregation_window < self.sample_period {
+            warn!("aggregation window ({} ms) shorter than the sample period ({} ms)", self.aggregation_window, self.sample_period);
+        }
+        if !cli_args.derived_metrics_file.is_empty() {
+            match load_derived_metrics(&cli_args.derived_metrics_file) {
+                Ok(derived_metrics) => {
+                    for d in &derived_metrics {
+                        get_metric_id(&d.name);
+                    }
+                    debug!("derived metrics: {:?}", derived_metrics);
+                    self.derived_metrics = derived_metrics;
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        if !cli_args.alert_rules_file.is_empty() {
+            match AlertEngine::new(&cli_args.alert_rules_file) {
+                Ok(alert_engine) => {
+                    debug!("alert rules: {:?}", alert_engine.rules());
+                    self.alert_engine = Some(alert_engine);
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    exit(1);
+                }
+            }
+        }
         let memory_backend = MemoryBackend::new(cgroup_manager.clone());
         let cpu_backend = CpuBackend::new(cgroup_manager.clone());
+        let hugetlb_backend = HugetlbBackend::new(cgroup_manager.clone());
+        let cpufreq_backend = CpufreqBackend::new(cgroup_manager.clone());
         self.add_backend(Box::new(memory_backend));
         self.add_backend(Box::new(cpu_backend));
+        self.add_backend(Box::new(hugetlb_backend));
+        self.add_backend(Box::new(cpufreq_backend));
 
         if cli_args.enable_infiniband {
         }
@@ -115,9 +333,37 @@ impl BackendsManager {
         }
         if cli_args.enable_rapl {
         }
+        if cli_args.enable_network {
+            let network_backend = NetworkBackend::new(&cli_args.network_interfaces_include, &cli_args.network_interfaces_exclude);
+            self.add_backend(Box::new(network_backend));
+        }
+        if cli_args.enable_diskstats {
+            let disk_backend = DiskBackend::new(&cli_args.diskstats_devices_include, &cli_args.diskstats_devices_exclude, cli_args.diskstats_partitions);
+            self.add_backend(Box::new(disk_backend));
+        }
+        if cli_args.enable_nfs {
+            let nfs_backend = NfsBackend::new();
+            self.add_backend(Box::new(nfs_backend));
+        }
+        if cli_args.enable_health {
+            let health_backend = HealthBackend::new();
+            self.add_backend(Box::new(health_backend));
+        }
         if cli_args.enable_perfhw {
-            let perfhw_backend = PerfhwBackend::new(cgroup_manager);
-            self.add_backend(Box::new(perfhw_backend));
+            // perf counters of cgroups need privileges, the problems are reported before the backend produces nothing
+            let problems = perfhw::diagnose(&cli_args.cgroup_root_path, &cli_args.cgroup_path_suffix);
+            for problem in &problems {
+                error!("perfhw: {}", problem);
+            }
+            if problems.is_empty() {
+                let perfhw_backend = PerfhwBackend::new(cgroup_manager, cli_args.perfhw_per_core, &cli_args.perfhw_groups);
+                self.add_backend(Box::new(perfhw_backend));
+            } else if cli_args.perfhw_continue_on_error {
+                warn!("perfhw backend disabled");
+            } else {
+                error!("perf counters cannot be collected, use --perfhw-continue-on-error true to run without the perfhw backend");
+                exit(1);
+            }
         }
         debug!("Number of backend enabled : {}", (*self.backends).borrow().len());
     }
@@ -130,12 +376,21 @@ impl BackendsManager {
 // job_id -> (hostname, timestamp, version, vec of MetricValues)
     pub fn make_measure(&mut self, timestamp: i64, hostname: String) -> bool {
         let version = *METRICS_VERSION;
+        self.remove_ended_jobs();
+        if let Some(alert_engine) = &mut self.alert_engine {
+            alert_engine.reload_if_modified();
+        }
         if self.metrics_modified { // reset measurement if new metrics
             self.last_measurement = HashMap::new();
+            self.latest_values = HashMap::new();
+            self.previous_samples = HashMap::new();
+            self.windows = HashMap::new();
             self.metrics_modified=false;
         }
+        self.collected_mode_metrics.clear();
         if self.last_timestamp==0 { //first exec of the loop
             self.last_timestamp=timestamp;
+            self.window_start=timestamp;
         }
         /*for m in self.metrics_to_get.clone() {
             debug!("{} {:?}", m.metric_name, m.time_remaining_before_next_measure);
@@ -144,34 +399,279 @@ impl BackendsManager {
         self.last_timestamp=timestamp;
         let mut list_metrics=self.get_metrics_to_collect_now(delta_t);
         if list_metrics.is_empty() {
-            return false;
+            return self.close_aggregation_window(timestamp, &hostname, version);
         }
         //debug!("list of metrics to get now (delta_t :{}) {:?}\n", delta_t, list_metrics);
         for meas in self.last_measurement.values_mut() {
             meas.1 = timestamp;
         }
-        let cp_b=(*self.backends).borrow();
+        let backends=Rc::clone(&self.backends);
+        let cp_b=(*backends).borrow();
         let b_iter=cp_b.iter();
+        // job_id -> metrics collected by this measure
+        let mut updated_metrics: HashMap<i32, Vec<String>> = HashMap::new();
         for backend in b_iter{
             if list_metrics.get_mut(&(backend.get_backend_name())).is_none(){
                 continue;
             }
-            for (job_id,mut metric) in backend.return_values(list_metrics.get_mut(&(backend.get_backend_name())).unwrap().clone()) {
+            for (job_id,metric) in backend.return_values(list_metrics.get_mut(&(backend.get_backend_name())).unwrap().clone()) {
                 debug!("metric values : {} {:?}", job_id, metric);
-                metric.metric_names=compress_metric_names(metric.metric_names);
-                if self.last_measurement.contains_key(&job_id) {
-                    // if some metrics have already been added for the same job_id
-                    let tmp=self.last_measurement.remove(&job_id).unwrap();
-                    self.last_measurement.insert(job_id,(tmp.0, tmp.1, tmp.2, self.update_measurement(tmp.3.clone(), metric)));
-                }else{
-                // if no metrics were added for the job_id
-                    let v:Vec<MetricValues>= vec![metric];
-                    self.last_measurement.insert(job_id, (hostname.clone(), timestamp, version, v.clone()));
+                if !self.derived_metrics.is_empty() || self.alert_engine.is_some() {
+                    let latest = self.latest_values.entry(job_id).or_default();
+                    for (name, value) in metric.metric_names.iter().zip(&metric.metric_values) {
+                        latest.insert(name.clone(), value.as_f64());
+                    }
+                    updated_metrics.entry(job_id).or_default().extend(metric.metric_names.iter().cloned());
                 }
+                self.add_metric_values(job_id, metric, &hostname, timestamp, version);
             }
         }
+        for (job_id, mut updated) in updated_metrics {
+            if let Some(metric) = self.compute_derived_metrics(job_id, &updated) {
+                debug!("derived metric values : {} {:?}", job_id, metric);
+                let latest = self.latest_values.entry(job_id).or_default();
+                for (name, value) in metric.metric_names.iter().zip(&metric.metric_values) {
+                    latest.insert(name.clone(), value.as_f64());
+                }
+                updated.extend(metric.metric_names.iter().cloned());
+                self.add_metric_values(job_id, metric, &hostname, timestamp, version);
+            }
+            if let (Some(alert_engine), Some(latest)) = (&mut self.alert_engine, self.latest_values.get(&job_id)) {
+                let events = alert_engine.evaluate(job_id, latest, &updated, timestamp, &hostname);
+                self.alert_events.extend(events);
+            }
+        }
+        if self.aggregation_window > 0 {
+            return self.close_aggregation_window(timestamp, &hostname, version);
+        }
         true
     }
+
+    // adds the values collected for a job to last_measurement, or to the current window if values are aggregated
+    fn add_metric_values(&mut self, job_id: i32, metric: MetricValues, hostname: &str, timestamp: i64, version: i64) {
+        let (metric, without_value) = self.apply_metric_modes(metric, timestamp);
+        if self.aggregation_window > 0 {
+            let stats = self.windows.entry(job_id).or_default().entry(metric.backend_name.clone()).or_default();
+            for (name, value) in metric.metric_names.into_iter().zip(metric.metric_values) {
+                if without_value.contains(&name) {
+                    continue;
+                }
+                match stats.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, s)) => s.add(value),
+                    None => {
+                        let with_percentiles = self.metric_aggregations.get(split_metric_name(&name).0)
+                            .is_some_and(|a| a.iter().any(|a| a.is_percentile()));
+                        stats.push((name, WindowStats::new(value, with_percentiles)));
+                    }
+                }
+            }
+            return;
+        }
+        self.insert_metric_values(job_id, metric, hostname, timestamp, version, &without_value);
+    }
+
+    // adds the aggregated values of the window to last_measurement when the window is over, returns true if there were values
+    fn close_aggregation_window(&mut self, timestamp: i64, hostname: &str, version: i64) -> bool {
+        if self.aggregation_window == 0 || timestamp - self.window_start < self.aggregation_window {
+            return false;
+        }
+        self.window_start = timestamp - (timestamp - self.window_start) % self.aggregation_window;
+        let windows = std::mem::take(&mut self.windows);
+        let mut done = false;
+        for (job_id, backends) in windows {
+            for (backend_name, stats) in backends {
+                let mut metric_names: Vec<String> = Vec::new();
+                let mut metric_values: Vec<MetricValue> = Vec::new();
+                for (name, s) in stats {
+                    let (metric_name, instance) = split_metric_name(&name);
+                    let aggregations = self.metric_aggregations.get(metric_name).map(|a| a.as_slice()).unwrap_or(&[Aggregation::Last]);
+                    for aggregation in aggregations {
+                        let aggregated_name = aggregation.metric_name(metric_name);
+                        metric_names.push(match instance {
+                            Some(instance) => instance_metric_name(&aggregated_name, instance),
+                            None => aggregated_name,
+                        });
+                        metric_values.push(s.value(*aggregation));
+                    }
+                }
+                let metric = MetricValues { job_id, backend_name, metric_names, metric_values };
+                debug!("aggregated metric values : {} {:?}", job_id, metric);
+                self.insert_metric_values(job_id, metric, hostname, timestamp, version, &[]);
+                done = true;
+            }
+        }
+        done
+    }
+
+    // adds values to last_measurement, metric names are replaced by their id
+    // without_value: metrics sent as delta or rate that have no value in this measure (see apply_metric_modes)
+    fn insert_metric_values(&mut self, job_id: i32, mut metric: MetricValues, hostname: &str, timestamp: i64, version: i64, without_value: &[String]) {
+        let ids = compress_metric_names(metric.metric_names.clone());
+        for (name, id) in metric.metric_names.iter().zip(&ids) {
+            if self.has_metric_mode(name) {
+                self.mode_metric_ids.insert(id.clone());
+                if !without_value.contains(name) {
+                    self.collected_mode_metrics.entry(job_id).or_default().insert(id.clone());
+                }
+            }
+        }
+        metric.metric_names=ids;
+        if self.last_measurement.contains_key(&job_id) {
+            // if some metrics have already been added for the same job_id
+            let tmp=self.last_measurement.remove(&job_id).unwrap();
+            self.last_measurement.insert(job_id,(tmp.0, tmp.1, tmp.2, self.update_measurement(tmp.3.clone(), metric)));
+        }else{
+        // if no metrics were added for the job_id
+            let v:Vec<MetricValues>= vec![metric];
+            self.last_measurement.insert(job_id, (hostname.to_string(), timestamp, version, v.clone()));
+        }
+    }
+
+    // true if the metric is sent as delta or rate
+    fn has_metric_mode(&self, name: &str) -> bool {
+        self.metric_modes.get(split_metric_name(name).0).is_some_and(|mode| *mode != MetricMode::Raw)
+    }
+
+    // last_measurement without the values of the metrics sent as delta or rate that were not collected by the last measure
+    // (first sample, or metric with its own sampling period), they would be counted twice
+    pub fn measurement_to_send(&self) -> HashMap<i32, (String, i64, i64, Vec<MetricValues>)> {
+        let mut measurement = self.last_measurement.clone();
+        for (job_id, (_, _, _, metrics)) in measurement.iter_mut() {
+            let collected = self.collected_mode_metrics.get(job_id);
+            for metric in metrics.iter_mut() {
+                let (metric_names, metric_values) = metric.metric_names.iter().cloned().zip(metric.metric_values.iter().copied())
+                    .filter(|(id, _)| !self.mode_metric_ids.contains(id) || collected.is_some_and(|c| c.contains(id)))
+                    .unzip();
+                metric.metric_names = metric_names;
+                metric.metric_values = metric_values;
+            }
+            metrics.retain(|m| !m.metric_names.is_empty());
+        }
+        measurement
+    }
+
+    // replaces the values of the metrics sent as delta or rate, the mode of metric_name applies to all its instances (metric_name@instance)
+    // the first sample of a job only initializes the metric, a value lower than the previous one means the counter was reset
+    // a metric without value (first sample) keeps its collected value so that the measures of the backend keep the same metrics,
+    // its name is returned with the metrics without value, and it is not sent (see measurement_to_send)
+    fn apply_metric_modes(&mut self, metric: MetricValues, timestamp: i64) -> (MetricValues, Vec<String>) {
+        let mut without_value: Vec<String> = Vec::new();
+        if self.metric_modes.is_empty() {
+            return (metric, without_value);
+        }
+        let previous_samples = self.previous_samples.entry(metric.job_id).or_default();
+        let mut metric_names: Vec<String> = Vec::new();
+        let mut metric_values: Vec<MetricValue> = Vec::new();
+        for (name, value) in metric.metric_names.into_iter().zip(metric.metric_values) {
+            let mode = self.metric_modes.get(split_metric_name(&name).0).copied().unwrap_or(MetricMode::Raw);
+            if mode == MetricMode::Raw {
+                metric_names.push(name);
+                metric_values.push(value);
+                continue;
+            }
+            let previous = previous_samples.insert(name.clone(), (value, timestamp));
+            let (previous_value, previous_timestamp) = match previous {
+                Some(p) => p,
+                None => {
+                    metric_values.push(value);
+                    without_value.push(name.clone());
+                    metric_names.push(name);
+                    continue;
+                }
+            };
+            // after a reset the counter counted from 0
+            let delta = match (value, previous_value) {
+                (MetricValue::Int(v), MetricValue::Int(p)) => MetricValue::Int(if v >= p { v - p } else { v }),
+                (v, p) => MetricValue::Float(if v.as_f64() >= p.as_f64() { v.as_f64() - p.as_f64() } else { v.as_f64() }),
+            };
+            let elapsed = timestamp - previous_timestamp;
+            match mode {
+                MetricMode::Delta => metric_values.push(delta),
+                // timestamps are in milliseconds
+                _ if elapsed > 0 => metric_values.push(MetricValue::Float(delta.as_f64() * 1000. / elapsed as f64)),
+                _ => {
+                    metric_values.push(value);
+                    without_value.push(name.clone());
+                }
+            }
+            metric_names.push(name);
+        }
+        (MetricValues { metric_names, metric_values, ..metric }, without_value)
+    }
+
+    // derived metrics of the job that use a metric collected by this measure, computed with the last values of the other metrics
+    fn compute_derived_metrics(&self, job_id: i32, updated: &[String]) -> Option<MetricValues> {
+        let latest = self.latest_values.get(&job_id)?;
+        let mut metric_names: Vec<String> = Vec::new();
+        let mut metric_values: Vec<MetricValue> = Vec::new();
+        for d in &self.derived_metrics {
+            if !d.operands.iter().any(|o| updated.contains(o)) {
+                continue;
+            }
+            if let Some(v) = d.compute(latest) {
+                metric_names.push(d.name.clone());
+                metric_values.push(MetricValue::Float(v));
+            }
+        }
+        if metric_names.is_empty() {
+            return None;
+        }
+        Some(MetricValues {
+            job_id,
+            backend_name: "derived".to_string(),
+            metric_names,
+            metric_values,
+        })
+    }
+    // the next call to take_dynamic_metrics_event gives all the ids again (ex colmet-collector restarted)
+    pub fn resend_dynamic_metrics(&mut self) {
+        self.nb_dynamic_metrics_sent = 0;
+    }
+
+    // event giving the ids of all the metrics that are not in metrics_order.yml, None if no metric was added since the last event
+    pub fn take_dynamic_metrics_event(&mut self, timestamp: i64, hostname: String) -> Option<Event> {
+        let dynamic_ids = DYNAMIC_METRIC_IDS.lock().unwrap();
+        if dynamic_ids.len() == self.nb_dynamic_metrics_sent {
+            return None;
+        }
+        self.nb_dynamic_metrics_sent = dynamic_ids.len();
+        let details = dynamic_ids.iter().map(|(name, id)| (name.clone(), id.to_string())).collect();
+        Some(Event {
+            event_type: "dynamic_metrics".to_string(),
+            hostname,
+            timestamp,
+            job_id: -1,
+            details,
+        })
+    }
+
+    // alerts that fired or were resolved since the last call
+    pub fn take_alert_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.alert_events)
+    }
+
+    // let the backends release what they hold for the jobs that ended
+    pub fn remove_ended_jobs(&mut self) {
+        let removed_cgroups = match &self.cgroup_manager {
+            Some(cgroup_manager) => cgroup_manager.take_removed_cgroups(),
+            None => return,
+        };
+        for (job_id, cgroup_name) in removed_cgroups {
+            debug!("job {} ended", job_id);
+            for backend in (*self.backends).borrow().iter() {
+                backend.remove_job(job_id, &cgroup_name);
+            }
+            self.last_measurement.remove(&job_id);
+            self.latest_values.remove(&job_id);
+            self.previous_samples.remove(&job_id);
+            self.collected_mode_metrics.remove(&job_id);
+            self.windows.remove(&job_id);
+            if let Some(alert_engine) = &mut self.alert_engine {
+                alert_engine.remove_job(job_id);
+            }
+        }
+    }
+
     pub fn sort_waiting_metrics(&mut self){
         self.metrics_to_get.sort_by_key(| k | k.time_remaining_before_next_measure);
     }
@@ -238,3 +738,83 @@ impl BackendsManager {
     self.metrics_modified=true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric_values(backend_name: &str, metric_names: &[&str], metric_values: &[i64]) -> MetricValues {
+        MetricValues {
+            job_id: 1,
+            backend_name: backend_name.to_string(),
+            metric_names: metric_names.iter().map(|n| n.to_string()).collect(),
+            metric_values: metric_values.iter().map(|v| MetricValue::Int(*v)).collect(),
+        }
+    }
+
+    fn names(measures: &[MetricValues]) -> Vec<(String, Vec<String>)> {
+        measures.iter().map(|m| (m.backend_name.clone(), m.metric_names.clone())).collect()
+    }
+
+    #[test]
+    fn test_update_measurement() {
+        let manager = BackendsManager::new(1., Vec::new());
+        let previous = vec![metric_values("memory", &["1", "2"], &[10, 20]), metric_values("cpu", &["3"], &[30])];
+
+        // the values of the same metrics are replaced
+        let res = manager.update_measurement(previous.clone(), metric_values("memory", &["1", "2"], &[11, 21]));
+        assert_eq!(names(&res), names(&previous));
+        assert!(matches!(res[0].metric_values[..], [MetricValue::Int(11), MetricValue::Int(21)]));
+
+        // other metrics of a backend are added next to the previous ones
+        let res = manager.update_measurement(previous.clone(), metric_values("memory", &["1", "2", "4"], &[11, 21, 41]));
+        assert_eq!(res.len(), 3);
+    }
+
+    fn sent(manager: &BackendsManager) -> Vec<(Vec<String>, Vec<f64>)> {
+        manager.measurement_to_send()[&1].3.iter()
+            .map(|m| (m.metric_names.clone(), m.metric_values.iter().map(|v| v.as_f64()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_metric_modes_not_collected() {
+        let mut manager = BackendsManager::new(1., Vec::new());
+        manager.metric_modes.insert("pgfault".to_string(), MetricMode::Rate);
+        let ids = compress_metric_names(vec!["rss".to_string(), "pgfault".to_string()]);
+        // the first sample of pgfault only initializes the rate, it is kept in last_measurement but not sent
+        manager.add_metric_values(1, metric_values("memory", &["rss", "pgfault"], &[10, 100]), "node", 1000, 0);
+        assert_eq!(manager.last_measurement[&1].3[0].metric_names, ids);
+        assert_eq!(sent(&manager), vec![(ids[..1].to_vec(), vec![10.])]);
+
+        // the next measure replaces the values of the same metrics
+        manager.collected_mode_metrics.clear();
+        manager.add_metric_values(1, metric_values("memory", &["rss", "pgfault"], &[20, 300]), "node", 2000, 0);
+        assert_eq!(manager.last_measurement[&1].3.len(), 1);
+        assert_eq!(sent(&manager), vec![(ids.clone(), vec![20., 200.])]);
+
+        // a measure that does not collect pgfault (ex it has its own sampling period) does not send its previous rate again
+        manager.collected_mode_metrics.clear();
+        assert_eq!(sent(&manager), vec![(ids[..1].to_vec(), vec![20.])]);
+
+        // the raw metrics are sent again as before
+        let mut manager = BackendsManager::new(1., Vec::new());
+        manager.add_metric_values(1, metric_values("memory", &["rss", "pgfault"], &[20, 300]), "node", 2000, 0);
+        manager.collected_mode_metrics.clear();
+        assert_eq!(sent(&manager), vec![(ids, vec![20., 300.])]);
+    }
+
+    #[test]
+    fn test_metric_modes_aggregated() {
+        let mut manager = BackendsManager::new(1., Vec::new());
+        manager.metric_modes.insert("pgfault".to_string(), MetricMode::Delta);
+        manager.aggregation_window = 10000;
+        manager.metric_aggregations.insert("pgfault".to_string(), vec![Aggregation::Sum]);
+        // the first sample of pgfault is not in the window
+        manager.add_metric_values(1, metric_values("memory", &["pgfault"], &[100]), "node", 1000, 0);
+        manager.add_metric_values(1, metric_values("memory", &["pgfault"], &[150]), "node", 2000, 0);
+        manager.add_metric_values(1, metric_values("memory", &["pgfault"], &[175]), "node", 3000, 0);
+        assert!(manager.close_aggregation_window(10000, "node", 0));
+        assert_eq!(sent(&manager), vec![(compress_metric_names(vec!["pgfault.sum".to_string()]), vec![75.])]);
+    }
+}
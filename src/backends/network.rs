@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::backends::metric::instance_metric_name;
+use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
+use crate::backends::metric::MetricValues;
+use crate::backends::{get_job_metrics, Backend};
+use crate::utils::NameFilter;
+
+// fields of a line of /proc/net/dev after the interface name, the names are the ones of /sys/class/net/<interface>/statistics
+const PROC_NET_DEV_FIELDS: [&str; 16] = [
+    "rx_bytes", "rx_packets", "rx_errors", "rx_dropped", "rx_fifo_errors", "rx_frame_errors", "rx_compressed", "multicast",
+    "tx_bytes", "tx_packets", "tx_errors", "tx_dropped", "tx_fifo_errors", "collisions", "tx_carrier_errors", "tx_compressed",
+];
+
+pub struct NetworkBackend {
+    pub backend_name: String,
+    interfaces_filter: NameFilter,
+}
+
+impl NetworkBackend {
+    pub fn new(include: &str, exclude: &str) -> NetworkBackend {
+        let backend_name = "network".to_string();
+        let interfaces_filter = NameFilter::new(include, exclude);
+
+        NetworkBackend {
+            backend_name,
+            interfaces_filter,
+        }
+    }
+}
+
+impl Backend for NetworkBackend {
+    fn say_hello(&self) {
+        println!("hello my name is network backend");
+    }
+
+    fn get_backend_name(&self) -> String {
+        self.backend_name.clone()
+    }
+
+    // network statistics are node-wide, they are associated to the fictive job 0
+    fn return_values(&self, metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues> {
+        let mut ret: HashMap<i32, MetricValues> = HashMap::new();
+        let node_metrics = get_job_metrics(&metrics_to_get, 0);
+        if node_metrics.is_empty() {
+            return ret;
+        }
+
+        let interfaces: Vec<(String, HashMap<String, i64>)> = read_proc_net_dev()
+            .into_iter()
+            .filter(|(interface, _)| self.interfaces_filter.is_selected(interface))
+            .collect();
+
+        let mut metric_names: Vec<String> = Vec::new();
+        let mut metric_values: Vec<i64> = Vec::new();
+        for m in node_metrics {
+            for (interface, proc_values) in &interfaces {
+                if let Some(v) = get_interface_value(interface, &m.metric_name, proc_values) {
+                    metric_names.push(instance_metric_name(&m.metric_name, interface));
+                    metric_values.push(v);
+                }
+            }
+        }
+
+        let metric = MetricValues {
+            job_id: 0,
+            backend_name: self.backend_name.clone(),
+            metric_names,
+            metric_values: metric_values.into_iter().map(MetricValue::Int).collect(),
+        };
+        ret.insert(0, metric);
+        ret
+    }
+}
+
+// the value is read from sysfs when available, /proc/net/dev is used otherwise (ex: sysfs of another network namespace)
+fn get_interface_value(interface: &str, metric_name: &str, proc_values: &HashMap<String, i64>) -> Option<i64> {
+    let filename = format!("/sys/class/net/{}/statistics/{}", interface, metric_name);
+    if Path::new(&filename).exists() {
+        if let Some(v) = fs::read_to_string(&filename).ok().and_then(|c| c.trim().parse::<i64>().ok()) {
+            return Some(v);
+        }
+    }
+    proc_values.get(metric_name).copied()
+}
+
+// returns the list of interfaces of /proc/net/dev with their counters, in the order of the file
+fn read_proc_net_dev() -> Vec<(String, HashMap<String, i64>)> {
+    match fs::read_to_string("/proc/net/dev") {
+        Ok(content) => parse_proc_net_dev(&content),
+        Err(e) => {
+            debug!("cannot read /proc/net/dev: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+fn parse_proc_net_dev(content: &str) -> Vec<(String, HashMap<String, i64>)> {
+    let mut res = Vec::new();
+    // the first two lines are headers
+    for line in content.lines().skip(2) {
+        let (interface, counters) = match line.split_once(':') {
+            Some(v) => v,
+            None => continue,
+        };
+        let mut h: HashMap<String, i64> = HashMap::new();
+        for (name, value) in PROC_NET_DEV_FIELDS.iter().zip(counters.split_whitespace()) {
+            if let Ok(v) = value.parse::<i64>() {
+                h.insert(name.to_string(), v);
+            }
+        }
+        res.push((interface.trim().to_string(), h));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROC_NET_DEV: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 1234567    8901    0    0    0     0          0         0  1234567    8901    0    0    0     0       0          0
+  eth0:98765432  123456    1    2    0     0          0        17 87654321  654321    0    3    0     0       0          0
+   ib0: 100 2
+ bond0: 10 - 30
+no colon here
+";
+
+    #[test]
+    fn test_parse_proc_net_dev() {
+        let res = parse_proc_net_dev(PROC_NET_DEV);
+        assert_eq!(res.iter().map(|(i, _)| i.as_str()).collect::<Vec<_>>(), vec!["lo", "eth0", "ib0", "bond0"]);
+        let eth0 = &res[1].1;
+        assert_eq!(eth0.len(), 16);
+        assert_eq!(eth0["rx_bytes"], 98765432);
+        assert_eq!(eth0["rx_dropped"], 2);
+        assert_eq!(eth0["multicast"], 17);
+        assert_eq!(eth0["tx_bytes"], 87654321);
+        assert_eq!(eth0["tx_dropped"], 3);
+        // a short line only gives its first counters
+        let ib0 = &res[2].1;
+        assert_eq!((ib0["rx_bytes"], ib0["rx_packets"], ib0.get("tx_bytes")), (100, 2, None));
+        // a field that is not a number is missing
+        let bond0 = &res[3].1;
+        assert_eq!((bond0["rx_bytes"], bond0.get("rx_packets"), bond0["rx_errors"]), (10, None, 30));
+    }
+
+    #[test]
+    fn test_parse_proc_net_dev_empty() {
+        assert!(parse_proc_net_dev("").is_empty());
+        // headers only
+        assert!(parse_proc_net_dev(&PROC_NET_DEV.lines().take(2).collect::<Vec<_>>().join("\n")).is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::backends::metric::instance_metric_name;
+use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
+use crate::backends::metric::MetricValues;
+use crate::backends::{get_job_metrics, Backend};
+
+// fields of the "bytes:" line of a nfs mount in /proc/self/mountstats
+const BYTES_FIELDS: [&str; 8] = [
+    "nfs_normal_read_bytes", "nfs_normal_write_bytes", "nfs_direct_read_bytes", "nfs_direct_write_bytes",
+    "nfs_server_read_bytes", "nfs_server_write_bytes", "nfs_read_pages", "nfs_write_pages",
+];
+
+// operations of the "per-op statistics" section that are reported, for nfs v3 and v4
+const MAIN_OPERATIONS: [&str; 11] = [
+    "READ", "WRITE", "COMMIT", "OPEN", "CLOSE", "ACCESS", "GETATTR", "SETATTR", "LOOKUP", "READDIR", "READDIRPLUS",
+];
+
+pub struct NfsBackend {
+    pub backend_name: String,
+}
+
+impl NfsBackend {
+    pub fn new() -> NfsBackend {
+        let backend_name = "nfs".to_string();
+
+        NfsBackend { backend_name }
+    }
+}
+
+impl Backend for NfsBackend {
+    fn say_hello(&self) {
+        println!("hello my name is nfs backend");
+    }
+
+    fn get_backend_name(&self) -> String {
+        self.backend_name.clone()
+    }
+
+    // nfs statistics are node-wide, they are associated to the fictive job 0
+    fn return_values(&self, metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues> {
+        let mut ret: HashMap<i32, MetricValues> = HashMap::new();
+        let node_metrics = get_job_metrics(&metrics_to_get, 0);
+        if node_metrics.is_empty() {
+            return ret;
+        }
+
+        let mounts = read_mountstats();
+
+        let mut metric_names: Vec<String> = Vec::new();
+        let mut metric_values: Vec<i64> = Vec::new();
+        for m in node_metrics {
+            for (mount_point, values) in &mounts {
+                if let Some(v) = values.get(&m.metric_name) {
+                    metric_names.push(instance_metric_name(&m.metric_name, mount_point));
+                    metric_values.push(*v);
+                }
+            }
+        }
+
+        let metric = MetricValues {
+            job_id: 0,
+            backend_name: self.backend_name.clone(),
+            metric_names,
+            metric_values: metric_values.into_iter().map(MetricValue::Int).collect(),
+        };
+        ret.insert(0, metric);
+        ret
+    }
+}
+
+// returns the list of nfs mount points of /proc/self/mountstats with their counters, in the order of the file
+// a mount starts with a line "device <export> mounted on <mount point> with fstype <nfs|nfs4> statvers=1.1"
+// each line of its per-op statistics is "<OP>: ops trans timeouts bytes_sent bytes_recv queue rtt execute [errors]", times in milliseconds
+fn read_mountstats() -> Vec<(String, HashMap<String, i64>)> {
+    match fs::read_to_string("/proc/self/mountstats") {
+        Ok(content) => parse_mountstats(&content),
+        Err(e) => {
+            debug!("cannot read /proc/self/mountstats: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+fn parse_mountstats(content: &str) -> Vec<(String, HashMap<String, i64>)> {
+    let mut res: Vec<(String, HashMap<String, i64>)> = Vec::new();
+    let mut in_nfs_mount = false;
+    let mut in_per_op = false;
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if line.starts_with("device ") {
+            in_per_op = false;
+            in_nfs_mount = fields.len() >= 8 && fields[6] == "fstype" && fields[7].starts_with("nfs");
+            if in_nfs_mount {
+                res.push((fields[4].to_string(), HashMap::new()));
+            }
+            continue;
+        }
+        if !in_nfs_mount || fields.is_empty() {
+            continue;
+        }
+        let values = &mut res.last_mut().unwrap().1;
+        if fields[0] == "bytes:" {
+            for (name, value) in BYTES_FIELDS.iter().zip(fields[1..].iter()) {
+                if let Ok(v) = value.parse::<i64>() {
+                    values.insert(name.to_string(), v);
+                }
+            }
+        } else if line.trim() == "per-op statistics" {
+            in_per_op = true;
+        } else if in_per_op && fields.len() >= 9 {
+            let operation = fields[0].trim_end_matches(':');
+            if !MAIN_OPERATIONS.contains(&operation) {
+                continue;
+            }
+            let counters: Vec<i64> = fields[1..9].iter().map(|v| v.parse::<i64>().unwrap_or(0)).collect();
+            let operation = operation.to_lowercase();
+            values.insert(format!("nfs_{}_ops", operation), counters[0]);
+            values.insert(format!("nfs_{}_timeouts", operation), counters[2]);
+            values.insert(format!("nfs_{}_rtt", operation), counters[6]);
+            values.insert(format!("nfs_{}_exe", operation), counters[7]);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTSTATS: &str = "\
+device sysfs mounted on /sys with fstype sysfs
+device server:/export/home mounted on /home with fstype nfs4 statvers=1.1
+\topts:\trw,vers=4.2,rsize=1048576,wsize=1048576,proto=tcp
+\tage:\t3600
+\tbytes:\t1000 2000 30 40 1030 2040 5 6
+\tRPC iostats version: 1.1  p/v: 100003/4 (nfs)
+\tper-op statistics
+\t        NULL: 1 1 0 44 24 0 0 0 0
+\t        READ: 10 11 1 1600 41000 2 35 40 0
+\t       WRITE: 20 20 0 82000 3200 5 60 70 1
+\t      RENAME: 3 3 0 600 400 0 2 2 0
+\t      LOOKUP: 7 7
+\t     GETATTR: 9 9 0 1400 2100 x 4 5 0
+device proc mounted on /proc with fstype proc
+\tREAD: 1 1 0 1 1 0 1 1 0
+device server:/export/scratch mounted on /scratch with fstype nfs statvers=1.1
+\tbytes:\t7 8
+";
+
+    #[test]
+    fn test_parse_mountstats() {
+        let res = parse_mountstats(MOUNTSTATS);
+        assert_eq!(res.iter().map(|(m, _)| m.as_str()).collect::<Vec<_>>(), vec!["/home", "/scratch"]);
+        let home = &res[0].1;
+        assert_eq!(home["nfs_normal_read_bytes"], 1000);
+        assert_eq!(home["nfs_write_pages"], 6);
+        assert_eq!((home["nfs_read_ops"], home["nfs_read_timeouts"], home["nfs_read_rtt"], home["nfs_read_exe"]), (10, 1, 35, 40));
+        assert_eq!((home["nfs_write_ops"], home["nfs_write_rtt"], home["nfs_write_exe"]), (20, 60, 70));
+        // operations that are not reported and short lines are skipped
+        assert!(!home.contains_key("nfs_null_ops") && !home.contains_key("nfs_rename_ops"));
+        assert!(!home.contains_key("nfs_lookup_ops"));
+        // a field that is not a number does not drop the other counters of the operation
+        assert_eq!(home["nfs_getattr_ops"], 9);
+        assert_eq!(home["nfs_getattr_rtt"], 4);
+        // a short bytes line only gives its first counters
+        let scratch = &res[1].1;
+        assert_eq!(scratch.len(), 2);
+        assert_eq!((scratch["nfs_normal_read_bytes"], scratch["nfs_normal_write_bytes"]), (7, 8));
+    }
+}
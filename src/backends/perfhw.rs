@@ -1,27 +1,125 @@
 extern crate gethostname;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
 
+use crate::backends::libperf_hw;
+use crate::backends::metric::instance_metric_name;
 use crate::backends::metric::Metric;
+use crate::backends::metric::MetricValue;
 use crate::backends::metric::MetricValues;
-use crate::backends::Backend;
-use crate::cgroup_manager::CgroupManager;
+use crate::backends::pmu;
+use crate::backends::{get_job_metrics, Backend};
+use crate::cgroup_manager::{controller_path, CgroupManager};
 
-use std::slice;
+const PERF_EVENT_PARANOID: &str = "/proc/sys/kernel/perf_event_paranoid";
+// bits of the capabilities in CapEff of /proc/self/status
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_PERFMON: u32 = 38;
 
-extern crate libc;
+// percentage of time each event was counting since the last read, reported as perfhw_running_percent@<event>
+// it is below 100 when the kernel multiplexes the events because there are more events than hardware counters
+pub(crate) const RUNNING_PERCENT: &str = "perfhw_running_percent";
 
 pub struct PerfhwBackend {
     pub backend_name: String,
     cgroup_manager: Arc<CgroupManager>,
+    per_core: bool, // one value per core of the job cpuset (metric_name@cpu<N>) instead of the sum over all cores
+    // events scheduled together on the hardware counters so that they are measured on the same time windows (ex instructions and cpu_cycles for IPC)
+    groups: Vec<Vec<String>>,
+    // events opened in libperf_hw for each job, in the order of their values
+    // the list only grows so that metrics with different sampling periods do not reopen the counters at each measure
+    job_events: RefCell<HashMap<i32, Vec<String>>>,
+    // counters are reset at each read, values of the events that were read but not sent yet are accumulated here
+    // job_id -> event -> values (one per core, or their sum)
+    pending_values: RefCell<HashMap<i32, HashMap<String, Vec<i64>>>>,
+    // job_id -> event -> running ratio of the last read
+    running_ratios: RefCell<HashMap<i32, HashMap<String, f64>>>,
+    // event -> description of the event given to libperf_hw, None if the event cannot be resolved
+    resolved_events: RefCell<HashMap<String, Option<String>>>,
+    // events that could not be opened for each job, their values are missing rather than 0
+    unsupported_events: RefCell<HashMap<i32, HashSet<String>>>,
 }
 
 impl PerfhwBackend {
-    pub fn new(cgroup_manager: Arc<CgroupManager>) -> PerfhwBackend { // this function is almost the same for all backends but there is no inheritance in rust, use composition ?
+    pub fn new(cgroup_manager: Arc<CgroupManager>, per_core: bool, groups: &str) -> PerfhwBackend { // this function is almost the same for all backends but there is no inheritance in rust, use composition ?
         let backend_name = "perfhw".to_string();
+        let job_events = RefCell::new(HashMap::new());
+        let pending_values = RefCell::new(HashMap::new());
+        let running_ratios = RefCell::new(HashMap::new());
+        let resolved_events = RefCell::new(HashMap::new());
+        let unsupported_events = RefCell::new(HashMap::new());
+        let groups = parse_groups(groups);
 
-        PerfhwBackend { backend_name, cgroup_manager }
+        PerfhwBackend { backend_name, cgroup_manager, per_core, groups, job_events, pending_values, running_ratios, resolved_events, unsupported_events }
+    }
+
+    // description of the event for libperf_hw: its name for the events known by the library, pmu:<type>:<config>:<config1>:<config2> for pmu and raw events
+    // sysfs is read only the first time the event is seen
+    fn resolve_event(&self, event: &str) -> Option<String> {
+        self.resolved_events.borrow_mut()
+            .entry(event.to_string())
+            .or_insert_with(|| match pmu::resolve_event(event) {
+                Ok(Some(description)) => {
+                    debug!("perfhw event {} resolved as {}", event, description);
+                    Some(description)
+                }
+                Ok(None) => Some(event.to_string()),
+                Err(e) => {
+                    warn!("perfhw event {} is ignored: {}", event, e);
+                    None
+                }
+            })
+            .clone()
+    }
+
+    // returns the values of each event (one per core, or their sum, scaled if the events were multiplexed) and the running ratio of each event
+    // the counters are opened if needed, the events that cannot be opened are then logged and recorded as unsupported
+    fn get_metric_values(&self, job_id: i32, perf_cgroup_name: &str, metric_names: &str, events: &[String]) -> Result<(Vec<Vec<i64>>, Vec<f64>), libperf_hw::PerfhwError> {
+        // nothing is done if the counters are already opened with the same metrics
+        if libperf_hw::open_counters(perf_cgroup_name, metric_names)? {
+            let mut unsupported = HashSet::new();
+            for (event, error) in events.iter().zip(libperf_hw::counter_errors(perf_cgroup_name, events.len())?) {
+                if let Some(e) = error {
+                    warn!("perfhw event {} of job {} is not counted: {}", event, job_id, e);
+                    unsupported.insert(event.clone());
+                }
+            }
+            self.unsupported_events.borrow_mut().insert(job_id, unsupported);
+        }
+        if self.per_core {
+            libperf_hw::read_counters_per_core(perf_cgroup_name, events.len())
+        } else {
+            libperf_hw::read_counters(perf_cgroup_name, events.len())
+                .map(|(values, ratios)| (values.into_iter().map(|v| vec![v]).collect(), ratios))
+        }
+    }
+
+    // splits the events of a job into the groups to open in libperf_hw, events that are not in a configured group are alone in their group
+    // an event in several configured groups is put in the first one
+    fn group_events(&self, events: &[String]) -> Vec<Vec<String>> {
+        let mut res: Vec<Vec<String>> = Vec::new();
+        let mut grouped: HashSet<String> = HashSet::new();
+        for group in &self.groups {
+            let members: Vec<String> = group.iter()
+                .filter(|e| events.contains(e) && !grouped.contains(*e))
+                .cloned()
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            grouped.extend(members.iter().cloned());
+            res.push(members);
+        }
+        for event in events {
+            if !grouped.contains(event) {
+                res.push(vec![event.clone()]);
+            }
+        }
+        res
     }
 }
 
@@ -34,78 +132,188 @@ impl Backend for PerfhwBackend {
         self.backend_name.clone()
     }
 
-    fn return_values(&self, mut metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues> {
+    fn return_values(&self, metrics_to_get: HashMap<i32, Vec<Metric>>) -> HashMap<i32, MetricValues> {
         let mut ret:HashMap<i32, MetricValues>=HashMap::new();
         let cgroups = self.cgroup_manager.get_cgroups();
         debug!("cgroup: {:#?}", cgroups);
 
         for (cgroup_id, cgroup_name) in cgroups {
+            let job_metrics = get_job_metrics(&metrics_to_get, cgroup_id);
+            if job_metrics.is_empty() {
+                continue;
+            }
             debug!(
                 "Getting cgroup name:= {}, with id:={}",
                 cgroup_name, cgroup_id
             );
-            let cgroup_name_string = format!("/oar/{}{}", cgroup_name, "\0").to_string();
-            let cgroup_name = cgroup_name_string.as_ptr();
-            let mut metric_names="".to_string();
-            
-            if metrics_to_get.get_mut(&cgroup_id).is_some() {
-                for m in metrics_to_get.get_mut(&cgroup_id).unwrap(){
-                    metric_names= format!("{} {}",  metric_names , m.metric_name);
+            let perf_cgroup_name = self.cgroup_manager.get_cgroup_path("perf_event", &cgroup_name);
+
+            let mut job_events = self.job_events.borrow_mut();
+            let events = job_events.entry(cgroup_id).or_default();
+            for m in &job_metrics {
+                if m.metric_name != RUNNING_PERCENT && !events.contains(&m.metric_name) && self.resolve_event(&m.metric_name).is_some() {
+                    events.push(m.metric_name.clone());
                 }
             }
-            if metrics_to_get.get(&(-1)).is_some() {
-                if metrics_to_get.get(&cgroup_id).is_none() {
-                    let v:Vec<Metric>=Vec::new();
-                    metrics_to_get.insert(cgroup_id, v);
-                }
-                for m in metrics_to_get.get(&(-1)).unwrap().clone() {
-                    if metric_names.is_empty() {
-                        metric_names=m.metric_name.to_string();
-                    }else{
-                        metric_names= format!("{},{}",  metric_names , m.metric_name);
-                    }
-                    metrics_to_get.get_mut(&cgroup_id).unwrap().push(m);
+            if events.is_empty() {
+                continue;
+            }
+            // the values are returned in the order of the events in the string, events of a group are joined by '+'
+            let groups = self.group_events(events);
+            let ordered_events: Vec<String> = groups.concat();
+            let metric_names = groups.iter()
+                .map(|g| g.iter().filter_map(|e| self.resolve_event(e)).collect::<Vec<String>>().join("+"))
+                .collect::<Vec<String>>()
+                .join(",");
+            debug!("Getting metrics: {}", metric_names);
+
+            let values = self.get_metric_values(cgroup_id, &perf_cgroup_name, &metric_names, &ordered_events);
+            let (values, ratios) = match values {
+                Ok(values) => values,
+                Err(e) => {
+                    warn!("could not get perfhw counters of job {}: {}", cgroup_id, e);
+                    continue;
                 }
+            };
+            let unsupported_events = self.unsupported_events.borrow();
+            let job_unsupported = unsupported_events.get(&cgroup_id);
+            let is_supported = |event: &String| !job_unsupported.is_some_and(|u| u.contains(event));
+
+            self.running_ratios.borrow_mut().insert(cgroup_id, ordered_events.iter().cloned().zip(ratios).collect());
 
+            let mut pending_values = self.pending_values.borrow_mut();
+            let job_pending = pending_values.entry(cgroup_id).or_default();
+            for (event, event_values) in ordered_events.iter().zip(values) {
+                if !is_supported(event) {
+                    continue;
+                }
+                let pending = job_pending.entry(event.clone()).or_default();
+                if pending.len() != event_values.len() {
+                    *pending = vec![0; event_values.len()];
+                }
+                for (p, v) in pending.iter_mut().zip(event_values) {
+                    *p += v;
+                }
             }
 
-            metric_names = format!("{}{}", metric_names, "\0");
-            debug!("Getting metrics: {}", metric_names);
-            let metric_values = get_metric_values(
-                cgroup_name,
-                metric_names.as_ptr(),
-                metrics_to_get.get(&cgroup_id).unwrap().len(),
-            ); // https://doc.rust-lang.org/std/ffi/struct.CString.html this type seems more appropriate but I cant get it to work
+            let cpus = if self.per_core { self.cgroup_manager.get_cpuset_cpus(&cgroup_name) } else { Vec::new() };
             let mut m_names: Vec<String>=Vec::new();
-            for m in metrics_to_get.get_mut(&cgroup_id).unwrap() {
-                m_names.push(m.metric_name.clone());
+            let mut metric_values: Vec<i64>=Vec::new();
+            for m in job_metrics {
+                if m.metric_name == RUNNING_PERCENT {
+                    if let Some(job_ratios) = self.running_ratios.borrow().get(&cgroup_id) {
+                        for event in events.iter() {
+                            if let (Some(ratio), true) = (job_ratios.get(event), is_supported(event)) {
+                                m_names.push(instance_metric_name(RUNNING_PERCENT, event));
+                                metric_values.push((ratio * 100.0).round() as i64);
+                            }
+                        }
+                    }
+                    continue;
+                }
+                let event_values = match job_pending.remove(&m.metric_name) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                if self.per_core {
+                    // the values are in the order of the cpus the counters are opened on, without cpuset all the cores are reported
+                    for (cpu, v) in libperf_hw::cpus().iter().zip(&event_values) {
+                        if cpus.is_empty() || cpus.contains(cpu) {
+                            m_names.push(instance_metric_name(&m.metric_name, &format!("cpu{}", cpu)));
+                            metric_values.push(*v);
+                        }
+                    }
+                } else {
+                    m_names.push(m.metric_name.clone());
+                    metric_values.push(event_values[0]);
+                }
             }
             let metric = MetricValues {
                 job_id: cgroup_id,
                 backend_name: self.backend_name.clone(),
                 metric_names: m_names,
-                metric_values,
+                metric_values: metric_values.into_iter().map(MetricValue::Int).collect(),
             };
             ret.insert(cgroup_id, metric);
         }
         ret
     }
-}
 
-fn get_metric_values(cgroup_name: *const u8, metrics_to_get: *const u8, nb_metrics_to_get: usize) -> Vec<i64> {
-    #[link(name = "perf_hw", kind="static")]
-    extern {
-        fn init_cgroup(cgroup_name: *const u8, metrics: *const u8) -> i32;
+    // close the perf counters of the job, every counter is a file descriptor
+    fn remove_job(&self, job_id: i32, cgroup_name: &str) {
+        self.pending_values.borrow_mut().remove(&job_id);
+        self.running_ratios.borrow_mut().remove(&job_id);
+        self.unsupported_events.borrow_mut().remove(&job_id);
+        if self.job_events.borrow_mut().remove(&job_id).is_none() {
+            return;
+        }
+        let perf_cgroup_name = self.cgroup_manager.get_cgroup_path("perf_event", cgroup_name);
+        let nb_counters = libperf_hw::nb_counters(&perf_cgroup_name).unwrap_or(0);
+        let nb_fds_before = count_open_fds();
+        libperf_hw::close_counters(&perf_cgroup_name);
+        let nb_fds_after = count_open_fds();
+        debug!("perfhw counters of job {} released: {} counters, {} file descriptors open", job_id, nb_counters, nb_fds_after);
+        // other threads may open files meanwhile, so only a leak is reported
+        if nb_counters > 0 && nb_fds_before.saturating_sub(nb_fds_after) < nb_counters {
+            warn!(
+                "perfhw counters of job {} may not be released: {} file descriptors closed for {} counters",
+                job_id,
+                nb_fds_before.saturating_sub(nb_fds_after),
+                nb_counters
+            );
+        }
     }
+}
 
-    extern "C" {
-        fn get_counters(values: *mut i64, cgroup_name: *const u8) -> i32;
+// reasons why the perf counters of the jobs cannot be opened, with what to do, empty if nothing prevents them
+pub fn diagnose(cgroup_root_path: &str, cgroup_path_suffix: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let paranoid = match fs::read_to_string(PERF_EVENT_PARANOID).ok().and_then(|c| c.trim().parse::<i32>().ok()) {
+        Some(paranoid) => paranoid,
+        None => {
+            problems.push(format!("{} cannot be read, the kernel does not support perf_event_open (CONFIG_PERF_EVENTS)", PERF_EVENT_PARANOID));
+            return problems;
+        }
+    };
+    // the counters of a cgroup are cpu-wide events, only allowed to unprivileged processes with perf_event_paranoid <= 0
+    let capabilities = read_effective_capabilities();
+    let privileged = capabilities & (1 << CAP_PERFMON | 1 << CAP_SYS_ADMIN) != 0;
+    debug!("perf_event_paranoid: {}, effective capabilities: {:#x}", paranoid, capabilities);
+    if paranoid > 0 && !privileged {
+        problems.push(format!(
+            "perf_event_paranoid is {} and colmet-node has neither CAP_PERFMON nor CAP_SYS_ADMIN, run it as root, \
+            give it CAP_PERFMON (setcap cap_perfmon+ep, linux >= 5.8) or set kernel.perf_event_paranoid to 0 (sysctl -w kernel.perf_event_paranoid=0)",
+            paranoid
+        ));
+    }
+    let perf_event_path = controller_path(cgroup_root_path, cgroup_path_suffix, "perf_event");
+    if !Path::new(&perf_event_path).exists() {
+        problems.push(format!(
+            "perf_event cgroup {} does not exist, mount the perf_event controller and make the resource manager create the job cgroups in it",
+            perf_event_path
+        ));
     }
-    // calling init_cgroup does nothing if the cgroup is already in the list
-    let _res = unsafe {init_cgroup(cgroup_name, metrics_to_get)};
-    let mut buffer = Vec::with_capacity(nb_metrics_to_get*64 as usize);
-    let buffer_ptr = buffer.as_mut_ptr();
-    let _res2 = unsafe {get_counters(buffer_ptr, cgroup_name)};
-    let metric_values = unsafe { slice::from_raw_parts(buffer_ptr, nb_metrics_to_get).to_vec()};
-    metric_values
+    problems
+}
+
+// CapEff of /proc/self/status, 0 if it cannot be read
+fn read_effective_capabilities() -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status.lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .unwrap_or(0)
+}
+
+// parses the groups of events given on the command line (ex instructions+cpu_cycles,cache_references+cache_misses)
+fn parse_groups(groups: &str) -> Vec<Vec<String>> {
+    groups.split(',')
+        .map(|group| group.split('+').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect::<Vec<String>>())
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+// number of file descriptors of the process
+fn count_open_fds() -> usize {
+    fs::read_dir("/proc/self/fd").map(|entries| entries.count()).unwrap_or(0)
 }
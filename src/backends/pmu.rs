@@ -0,0 +1,198 @@
+use std::fs;
+
+// events that are not in perf_static_info of libperf_hw are given to the library as "pmu:<type>:<config>:<config1>:<config2>"
+// they are named after the perf syntax: pmu/event/ for the events described in /sys/bus/event_source/devices/<pmu>/events, r<hex> for raw events
+// any event can be followed by the modifier :u (user mode only) or :k (kernel mode only), libperf_hw parses the modifier
+
+const EVENT_SOURCE_DEVICES: &str = "/sys/bus/event_source/devices";
+const PERF_TYPE_RAW: u32 = 4;
+
+// true if the metric name is a pmu or raw event (ex cpu/mem-loads/, r01c2:u)
+pub fn is_dynamic_event(name: &str) -> bool {
+    let (name, _) = split_modifier(name);
+    parse_raw_event(name).is_some() || parse_pmu_event(name).is_some()
+}
+
+// instructions:u -> (instructions, Some(u))
+pub fn split_modifier(name: &str) -> (&str, Option<&str>) {
+    match name.rsplit_once(':') {
+        Some((event, modifier)) if modifier == "u" || modifier == "k" => (event, Some(modifier)),
+        _ => (name, None),
+    }
+}
+
+// returns the description of the event for libperf_hw, None for the events of perf_static_info, an error if the event cannot be resolved
+pub fn resolve_event(name: &str) -> Result<Option<String>, String> {
+    let (event, modifier) = split_modifier(name);
+    let description = if let Some(config) = parse_raw_event(event) {
+        format_event(PERF_TYPE_RAW, [config, 0, 0])
+    } else {
+        match parse_pmu_event(event) {
+            Some((pmu, event)) => resolve_pmu_event(pmu, event)?,
+            None => return Ok(None),
+        }
+    };
+    match modifier {
+        Some(modifier) => Ok(Some(format!("{}:{}", description, modifier))),
+        None => Ok(Some(description)),
+    }
+}
+
+fn format_event(perf_type: u32, config: [u64; 3]) -> String {
+    format!("pmu:{:x}:{:x}:{:x}:{:x}", perf_type, config[0], config[1], config[2])
+}
+
+// r<hex> -> config
+fn parse_raw_event(name: &str) -> Option<u64> {
+    let code = name.strip_prefix('r')?;
+    if code.is_empty() {
+        return None;
+    }
+    u64::from_str_radix(code, 16).ok()
+}
+
+// pmu/event/ -> (pmu, event)
+fn parse_pmu_event(name: &str) -> Option<(&str, &str)> {
+    let (pmu, event) = name.strip_suffix('/')?.split_once('/')?;
+    if pmu.is_empty() || event.is_empty() || event.contains('/') {
+        return None;
+    }
+    Some((pmu, event))
+}
+
+// the type of the pmu is in /sys/bus/event_source/devices/<pmu>/type
+// the event is described by terms (ex event=0xd0,umask=0x81) whose bits in config, config1 and config2 are given by the files of format/
+fn resolve_pmu_event(pmu: &str, event: &str) -> Result<String, String> {
+    let pmu_path = format!("{}/{}", EVENT_SOURCE_DEVICES, pmu);
+    let perf_type = fs::read_to_string(format!("{}/type", pmu_path))
+        .map_err(|e| format!("unknown pmu {}: {}", pmu, e))?
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| format!("invalid type of pmu {}: {}", pmu, e))?;
+    let description = fs::read_to_string(format!("{}/events/{}", pmu_path, event))
+        .map_err(|e| format!("unknown event {} of pmu {}: {}", event, pmu, e))?;
+
+    let mut config: [u64; 3] = [0; 3];
+    for term in description.trim().split(',').filter(|t| !t.is_empty()) {
+        // a term without value is a flag
+        let (key, value) = term.split_once('=').unwrap_or((term, "1"));
+        let value = parse_term_value(value).ok_or(format!("invalid value of {} in event {}", key, event))?;
+        match fs::read_to_string(format!("{}/format/{}", pmu_path, key)) {
+            Ok(format) => {
+                let (field, ranges) = parse_format(format.trim()).ok_or(format!("invalid format of {} for pmu {}", key, pmu))?;
+                place_bits(&mut config[field], &ranges, value);
+            }
+            // config, config1 and config2 can be given directly
+            Err(_) => match config_field(key) {
+                Some(field) => config[field] |= value,
+                None => debug!("ignoring term {} of event {}", key, event),
+            },
+        }
+    }
+    Ok(format_event(perf_type, config))
+}
+
+fn parse_term_value(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse::<u64>().ok(),
+    }
+}
+
+fn config_field(name: &str) -> Option<usize> {
+    match name {
+        "config" => Some(0),
+        "config1" => Some(1),
+        "config2" => Some(2),
+        _ => None,
+    }
+}
+
+// config:0-7,32-35 -> (0, [(0, 7), (32, 35)]), config1:21 -> (1, [(21, 21)])
+fn parse_format(format: &str) -> Option<(usize, Vec<(u32, u32)>)> {
+    let (field, bits) = format.split_once(':')?;
+    let field = config_field(field)?;
+    let mut ranges = Vec::new();
+    for range in bits.split(',') {
+        let (low, high) = range.split_once('-').unwrap_or((range, range));
+        let (low, high) = (low.parse::<u32>().ok()?, high.parse::<u32>().ok()?);
+        if low > high || high > 63 {
+            return None;
+        }
+        ranges.push((low, high));
+    }
+    Some((field, ranges))
+}
+
+// the low bits of value fill the first range, the next ones the second range...
+fn place_bits(config: &mut u64, ranges: &[(u32, u32)], value: u64) {
+    let mut value = value;
+    for (low, high) in ranges {
+        for bit in *low..=*high {
+            *config |= (value & 1) << bit;
+            value >>= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Format = Option<(usize, Vec<(u32, u32)>)>;
+    type Placement = (&'static [(u32, u32)], u64, u64);
+
+    #[test]
+    fn test_parse_format() {
+        let cases: Vec<(&str, Format)> = vec![
+            ("config:0-7", Some((0, vec![(0, 7)]))),
+            ("config1:0-15,32-35", Some((1, vec![(0, 15), (32, 35)]))),
+            ("config:21", Some((0, vec![(21, 21)]))),
+            ("config2:3,5", Some((2, vec![(3, 3), (5, 5)]))),
+            ("config:0-63", Some((0, vec![(0, 63)]))),
+            ("config:7-0", None),
+            ("config:0-64", None),
+            ("config3:0-7", None),
+            ("config", None),
+            ("config:a-b", None),
+        ];
+        for (format, expected) in cases {
+            assert_eq!(parse_format(format), expected, "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_place_bits() {
+        let cases: [Placement; 7] = [
+            (&[(0, 7)], 0xd0, 0xd0),
+            (&[(8, 15)], 0x81, 0x8100),
+            // the value is truncated to the width of the ranges
+            (&[(0, 7)], 0x1ff, 0xff),
+            // the low bits go in the first range, the next ones in the second range
+            (&[(0, 15), (32, 35)], 0x5_1234, 0x5_0000_1234),
+            (&[(21, 21)], 1, 1 << 21),
+            (&[(21, 21)], 0, 0),
+            (&[(3, 3), (5, 5)], 0b10, 1 << 5),
+        ];
+        for (ranges, value, expected) in cases {
+            let mut config = 0;
+            place_bits(&mut config, ranges, value);
+            assert_eq!(config, expected, "{:?} {:#x}", ranges, value);
+        }
+        // the bits already set are kept
+        let mut config = 0xf000;
+        place_bits(&mut config, &[(0, 7)], 0x12);
+        assert_eq!(config, 0xf012);
+    }
+
+    #[test]
+    fn test_parse_raw_event() {
+        assert_eq!(parse_raw_event("r01c2"), Some(0x1c2));
+        assert_eq!(parse_raw_event("r1a8"), Some(0x1a8));
+        assert_eq!(parse_raw_event("r"), None);
+        assert_eq!(parse_raw_event("r0x1a8"), None);
+        assert_eq!(parse_raw_event("rss"), None);
+        assert!(is_dynamic_event("r01c2:u"));
+        assert!(!is_dynamic_event("rss"));
+    }
+}
@@ -4,7 +4,7 @@ extern crate regex;
 use std::borrow::BorrowMut;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
@@ -15,19 +15,25 @@ use inotify::{
 };
 use regex::Regex;
 
+use crate::utils::parse_cpu_list;
+
 
 pub struct CgroupManager {
     pub cgroup_root_path: String, // cgroup root path
     pub cgroup_path_suffix: String,
+    pub cgroup_v2: bool, // true if cgroup_root_path is a unified hierarchy (cgroup v2)
     cgroups: Mutex<HashMap<i32, String>>, // cgroup corresponding to user jobs, keys : cgroup id, values : cgroup name
+    removed_cgroups: Mutex<Vec<(i32, String)>>, // cgroups removed since the last call to take_removed_cgroups
     regex_job_id: String, // regex to find the cpuset directory
 }
 
 impl CgroupManager {
     pub fn new(regex_job_id: String, cgroup_root_path: String, cgroup_path_suffix: String) -> Arc<CgroupManager> {
         let cgroups = Mutex::new(HashMap::new());
-        let cgroup_path = format!("{}/cpuset{}", cgroup_root_path, cgroup_path_suffix);
-        let res = Arc::new(CgroupManager { cgroup_root_path, cgroup_path_suffix, cgroups, regex_job_id });
+        let removed_cgroups = Mutex::new(Vec::new());
+        let cgroup_v2 = is_cgroup_v2(&cgroup_root_path);
+        let cgroup_path = controller_path(&cgroup_root_path, &cgroup_path_suffix, "cpuset");
+        let res = Arc::new(CgroupManager { cgroup_root_path, cgroup_path_suffix, cgroup_v2, cgroups, removed_cgroups, regex_job_id });
         notify_jobs(Arc::clone(&res), cgroup_path);
         res
     }
@@ -41,18 +47,65 @@ impl CgroupManager {
 
     pub fn remove_cgroup(&self, id: i32) {
         let mut map = self.cgroups.lock().unwrap();
-        map.borrow_mut().remove(&id);
+        if let Some(name) = map.borrow_mut().remove(&id) {
+            self.removed_cgroups.lock().unwrap().push((id, name));
+        }
+    }
+
+    // jobs that ended since the last call, so that backends can release what they hold for them
+    pub fn take_removed_cgroups(&self) -> Vec<(i32, String)> {
+        std::mem::take(&mut *self.removed_cgroups.lock().unwrap())
     }
 
     pub fn get_cgroups(&self) -> HashMap<i32, String> {
         self.cgroups.lock().unwrap().clone()
     }
 
+    // directory of the cgroup of a job for the given controller (ex: "hugetlb", "memory")
+    pub fn get_cgroup_path(&self, controller: &str, cgroup_name: &str) -> String {
+        if self.cgroup_v2 {
+            format!("{}{}/{}", self.cgroup_root_path, self.cgroup_path_suffix, cgroup_name)
+        } else {
+            format!("{}/{}{}/{}", self.cgroup_root_path, controller, self.cgroup_path_suffix, cgroup_name)
+        }
+    }
+
+    // cpus of the cpuset of a job, cgroup v2 only exposes the cpus actually usable in cpuset.cpus.effective
+    pub fn get_cpuset_cpus(&self, cgroup_name: &str) -> Vec<u32> {
+        let filename = if self.cgroup_v2 {
+            format!("{}/cpuset.cpus.effective", self.get_cgroup_path("cpuset", cgroup_name))
+        } else {
+            format!("{}/cpuset.cpus", self.get_cgroup_path("cpuset", cgroup_name))
+        };
+        match fs::read_to_string(&filename) {
+            Ok(content) => parse_cpu_list(&content),
+            Err(e) => {
+                debug!("cannot read {}: {}", filename, e);
+                Vec::new()
+            }
+        }
+    }
+
     pub fn print_cgroups(&self) {
         println!("{:#?}", self.cgroups);
     }
 }
 
+// the unified hierarchy (cgroup v2) exposes cgroup.controllers at its root
+pub fn is_cgroup_v2(cgroup_root_path: &str) -> bool {
+    Path::new(&format!("{}/cgroup.controllers", cgroup_root_path)).exists()
+}
+
+// directory containing the job cgroups for the given controller
+// with cgroup v1 each controller has its own hierarchy, with cgroup v2 all controllers share the same one
+pub fn controller_path(cgroup_root_path: &str, cgroup_path_suffix: &str, controller: &str) -> String {
+    if is_cgroup_v2(cgroup_root_path) {
+        format!("{}{}", cgroup_root_path, cgroup_path_suffix)
+    } else {
+        format!("{}/{}{}", cgroup_root_path, controller, cgroup_path_suffix)
+    }
+}
+
 // scan cpuset directory for changes and update cgroups list
 pub fn notify_jobs(cgroup_manager: Arc<CgroupManager>, cgroup_path: String) {
     let regex_job_id = Regex::new(&cgroup_manager.regex_job_id).unwrap();
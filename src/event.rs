@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+
+// something that happened to a job (ex: a process killed by the oom killer), sent to colmet-collector as soon as it is detected
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub event_type: String,
+    pub hostname: String,
+    pub timestamp: i64,
+    pub job_id: i32,
+    pub details: HashMap<String, String>,
+}
+
+impl Serialize for Event {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where
+        S:Serializer,
+    {
+        let mut state = serializer.serialize_struct("Event", 5)?;
+        state.serialize_field("event_type", &self.event_type).expect("error when serializing event_type in Event");
+        state.serialize_field("hostname", &self.hostname).expect("error when serializing hostname in Event");
+        state.serialize_field("timestamp", &self.timestamp).expect("error when serializing timestamp in Event");
+        state.serialize_field("job_id", &self.job_id).expect("error when serializing job_id in Event");
+        state.serialize_field("details", &self.details).expect("error when serializing details in Event");
+        state.end()
+    }
+}
@@ -28,6 +28,8 @@ use crate::backends::metric::Metric;
 
 mod backends;
 mod cgroup_manager;
+mod event;
+mod oom_monitor;
 mod utils;
 mod zeromq;
 
@@ -41,10 +43,10 @@ fn main(){
     if cli_args.verbose>=3 {
         debug!("{}", debug_list_metrics(cli_args.clone()));
     }
-    let cgroup_cpuset_path = format!(
-        "{}/cpuset{}",
-        cli_args.cgroup_root_path,
-        cli_args.cgroup_path_suffix
+    let cgroup_cpuset_path = cgroup_manager::controller_path(
+        &cli_args.cgroup_root_path,
+        &cli_args.cgroup_path_suffix,
+        "cpuset"
     );
     //let backends_manager_ref = Rc::new(RefCell::new(BackendsManager::new(cli_args.metrics_to_get.clone())));
     let mut backend_manager=BackendsManager::new(cli_args.sample_period, cli_args.metrics_to_get.clone());
@@ -57,13 +59,20 @@ fn main(){
                                             cli_args.cgroup_path_suffix.clone(),);
 
 
-    backend_manager.init_backends(cli_args.clone(), cgroup_manager);
-    let zmq_sender = zeromq::ZmqSender::init();
-    zmq_sender.open(&cli_args.zeromq_uri, cli_args.zeromq_linger, cli_args.zeromq_hwm);
+    backend_manager.init_backends(cli_args.clone(), cgroup_manager.clone());
+    let mut zmq_sender = zeromq::ZmqSender::init();
+    zmq_sender.open(&cli_args.zeromq_uri, &cli_args.zeromq_events_uri, cli_args.zeromq_linger, cli_args.zeromq_hwm);
 
     let hostname: String = gethostname::gethostname().to_str().unwrap().to_string();
+    let oom_events = if cli_args.enable_oom_detection {
+        Some(oom_monitor::start(cgroup_manager, hostname.clone()))
+    } else {
+        None
+    };
     let max_value_recv_counter = (1./cli_args.sample_period) as i32;
     let mut counter = 0;
+    let metrics_metadata_period = (cli_args.metrics_metadata_period * 1000.) as i64;
+    let mut metrics_metadata_sent: Option<i64> = None;
     
     // main loop that pull backends measurements periodically and send them with zeromq
     loop {
@@ -88,15 +97,43 @@ fn main(){
             counter += 1;
         }
 
+        // the metadata is sent at start, periodically and each time the events socket connects again (colmet-collector restarted)
+        let events_connected = zmq_sender.events_connected();
+        if cli_args.send_metrics_metadata {
+            let due = match metrics_metadata_sent {
+                None => true,
+                Some(sent) => metrics_metadata_period > 0 && timestamp - sent >= metrics_metadata_period,
+            };
+            if due || events_connected {
+                zmq_sender.send_event(backends::metrics_metadata_event(timestamp, hostname.clone()));
+                metrics_metadata_sent = Some(timestamp);
+            }
+        }
+
         let time_to_take_measure=now.elapsed().unwrap().as_nanos();
+        // ids of the metrics that are not in metrics_order.yml must be known by colmet-collector before it decodes the metrics
+        if events_connected {
+            backend_manager.resend_dynamic_metrics();
+        }
+        if let Some(event) = backend_manager.take_dynamic_metrics_event(timestamp, hostname.clone()) {
+            zmq_sender.send_event(event);
+        }
         if measure_done {
             debug!("time to take measures {} microseconds", time_to_take_measure/1000);
-            let m = backend_manager.last_measurement.clone();
+            let m = backend_manager.measurement_to_send();
             debug!("collected metrics : {:?}", m);
             zmq_sender.send_metrics(m);
         }else{
             debug!("Measure not done /o\\");
         }
+        for event in backend_manager.take_alert_events() {
+            zmq_sender.send_event(event);
+        }
+        if let Some(events) = &oom_events {
+            for event in events.try_iter() {
+                zmq_sender.send_event(event);
+            }
+        }
         sleep_to_round_timestamp(backend_manager.get_sleep_time());
     }
 }
@@ -119,34 +156,80 @@ pub struct CliArgs {
     enable_infiniband: bool,
     enable_lustre: bool,
     enable_perfhw: bool,
+    perfhw_per_core: bool,
+    perfhw_groups: String,
+    perfhw_continue_on_error: bool,
     enable_rapl: bool,
+    enable_network: bool,
+    network_interfaces_include: String,
+    network_interfaces_exclude: String,
+    enable_diskstats: bool,
+    diskstats_devices_include: String,
+    diskstats_devices_exclude: String,
+    diskstats_partitions: bool,
+    enable_nfs: bool,
+    enable_health: bool,
+    enable_oom_detection: bool,
     zeromq_uri: String,
+    zeromq_events_uri: String,
+    send_metrics_metadata: bool,
+    metrics_metadata_period: f32,
     zeromq_hwm: i32,
     zeromq_linger: i32,
     cgroup_root_path: String,
     cgroup_path_suffix: String,
     wait_cgroup_cpuset_path: bool,
     regex_job_id: String,
+    derived_metrics_file: String,
+    metric_modes: String,
+    aggregation_window: f32,
+    metric_aggregations: String,
+    alert_rules_file: String,
     metrics_to_get: Vec<Metric>  
 }
 
 fn parse_cli_args() -> CliArgs {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
+    if matches.is_present("list-metrics") {
+        print!("{}", backends::list_metrics());
+        exit(0);
+    }
     let verbose = value_t!(matches, "verbose", i32).unwrap();
     let sample_period = value_t!(matches, "sample-period", f32).unwrap();
     debug!("sample period {}", sample_period);
     let enable_infiniband = value_t!(matches, "enable-infiniband", bool).unwrap();
     let enable_lustre = value_t!(matches, "enable-lustre", bool).unwrap();
     let enable_perfhw = value_t!(matches, "enable-perfhw", bool).unwrap();
+    let perfhw_per_core = value_t!(matches, "perfhw-per-core", bool).unwrap();
+    let perfhw_groups = value_t!(matches, "perfhw-groups", String).unwrap();
+    let perfhw_continue_on_error = value_t!(matches, "perfhw-continue-on-error", bool).unwrap();
     let enable_rapl = value_t!(matches, "enable-RAPL", bool).unwrap();
+    let enable_network = value_t!(matches, "enable-network", bool).unwrap();
+    let network_interfaces_include = value_t!(matches, "network-interfaces-include", String).unwrap();
+    let network_interfaces_exclude = value_t!(matches, "network-interfaces-exclude", String).unwrap();
+    let enable_diskstats = value_t!(matches, "enable-diskstats", bool).unwrap();
+    let diskstats_devices_include = value_t!(matches, "diskstats-devices-include", String).unwrap();
+    let diskstats_devices_exclude = value_t!(matches, "diskstats-devices-exclude", String).unwrap();
+    let diskstats_partitions = value_t!(matches, "diskstats-partitions", bool).unwrap();
+    let enable_nfs = value_t!(matches, "enable-nfs", bool).unwrap();
+    let enable_health = value_t!(matches, "enable-health", bool).unwrap();
+    let enable_oom_detection = value_t!(matches, "enable-oom-detection", bool).unwrap();
     let zeromq_uri = value_t!(matches, "zeromq-uri", String).unwrap();
+    let zeromq_events_uri = value_t!(matches, "zeromq-events-uri", String).unwrap();
+    let send_metrics_metadata = value_t!(matches, "send-metrics-metadata", bool).unwrap();
+    let metrics_metadata_period = value_t!(matches, "metrics-metadata-period", f32).unwrap();
     let zeromq_hwm = value_t!(matches, "zeromq-hwm", i32).unwrap();
     let zeromq_linger = value_t!(matches, "zeromq-linger", i32).unwrap();
     let cgroup_root_path = value_t!(matches, "cgroup-root-path", String).unwrap();
     let cgroup_path_suffix = value_t!(matches, "cgroup-path-suffix", String).unwrap();
     let wait_cgroup_cpuset_path = value_t!(matches, "wait-cgroup-cpuset-path", bool).unwrap();
     let regex_job_id = value_t!(matches, "regex-job-id", String).unwrap();
+    let derived_metrics_file = value_t!(matches, "derived-metrics-file", String).unwrap();
+    let metric_modes = value_t!(matches, "metric-modes", String).unwrap();
+    let aggregation_window = value_t!(matches, "aggregation-window", f32).unwrap();
+    let metric_aggregations = value_t!(matches, "metric-aggregations", String).unwrap();
+    let alert_rules_file = value_t!(matches, "alert-rules-file", String).unwrap();
 
     let metrics_file = value_t!(matches, "file_metrics", String).unwrap();
     let mut metrics_to_get: Vec<Metric> = Vec::new();
@@ -186,14 +269,35 @@ fn parse_cli_args() -> CliArgs {
         enable_infiniband,
         enable_lustre,
         enable_perfhw,
+        perfhw_per_core,
+        perfhw_groups,
+        perfhw_continue_on_error,
         enable_rapl,
+        enable_network,
+        network_interfaces_include,
+        network_interfaces_exclude,
+        enable_diskstats,
+        diskstats_devices_include,
+        diskstats_devices_exclude,
+        diskstats_partitions,
+        enable_nfs,
+        enable_health,
+        enable_oom_detection,
         zeromq_uri,
+        zeromq_events_uri,
+        send_metrics_metadata,
+        metrics_metadata_period,
         zeromq_hwm,
         zeromq_linger,
         cgroup_root_path,
         cgroup_path_suffix,
         wait_cgroup_cpuset_path,
         regex_job_id,
+        derived_metrics_file,
+        metric_modes,
+        aggregation_window,
+        metric_aggregations,
+        alert_rules_file,
         metrics_to_get
     } 
 }
@@ -206,18 +310,20 @@ fn parse_metrics(arg_string: String) -> Option<Vec<Metric>> {
     let mut n:String;
     let mut j:i32;
     for arg in args{
-        let v:Vec<&str>=arg.split(':').collect();
+        let mut v:Vec<&str>=arg.split(':').collect();
+        // perfhw events can have a modifier (ex instructions:u), it is part of the metric name
+        let name=if (v.len()==2 || v.len()==4) && (v[1]=="u" || v[1]=="k") { let modifier=v.remove(1); format!("{}:{}", v[0], modifier) } else { v[0].to_string() };
         if v.len()==1 {
-            n=v[0].to_string();
+            n=name;
             s = -1.;
             j = -1;
         }else if v.len()==3 {
-            n=v[0].to_string();
+            n=name;
             s=v[1].to_string().parse::<f32>().unwrap();
             j=v[2].to_string().parse::<i32>().unwrap();
         }
         else{
-            println!("Error while parsing metrics. Correct format is 'metric_name:sampling_period:job_id,...'. Sampling_period and job_id can be omited (they are set to -1).");
+            println!("Error while parsing metrics. Correct format is 'metric_name[:modifier]:sampling_period:job_id,...'. Sampling_period and job_id can be omited (they are set to -1).");
             correct=false;
             break;
         }
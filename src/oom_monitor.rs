@@ -0,0 +1,381 @@
+extern crate libc;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+use regex::Regex;
+
+use crate::cgroup_manager::CgroupManager;
+use crate::event::Event;
+
+// maximum time between two checks of the oom_kill counters, in milliseconds
+const CHECK_PERIOD: i32 = 1000;
+
+lazy_static! {
+    // kernel >= 4.19, gives the memory cgroup of the killed task
+    static ref REGEX_OOM_KILL: Regex = Regex::new(r"oom-kill:.*task_memcg=([^,]*),task=([^,]*),pid=(\d+)").unwrap();
+    static ref REGEX_KILLED_PROCESS: Regex =
+        Regex::new(r"Killed process (\d+) \((.*)\) total-vm:\d+kB, anon-rss:(\d+)kB, file-rss:(\d+)kB").unwrap();
+}
+
+// a process killed by the oom killer, as reported by the kernel in /dev/kmsg
+#[derive(Debug, Clone, Default)]
+struct KilledProcess {
+    pid: String,
+    name: String,
+    memcg: String, // memory cgroup of the killed task, only known with kernel >= 4.19
+    anon_rss: String,
+    file_rss: String,
+}
+
+struct JobOomState {
+    cgroup_name: String,
+    oom_kill: Option<i64>, // last value of the oom_kill counter of the cgroup
+    eventfd: Option<(RawFd, File)>, // cgroup v1 oom notification: eventfd and memory.oom_control it is registered on
+}
+
+impl Drop for JobOomState {
+    fn drop(&mut self) {
+        if let Some((efd, _)) = self.eventfd {
+            unsafe { libc::close(efd) };
+        }
+    }
+}
+
+pub struct OomMonitor {
+    cgroup_manager: Arc<CgroupManager>,
+    hostname: String,
+    sender: Sender<Event>,
+    kmsg: Option<File>,
+    killed_processes: Vec<KilledProcess>,
+    jobs: HashMap<i32, JobOomState>,
+}
+
+// start the detection of oom kills in the jobs, events are received on the returned channel
+// oom kills are detected with the oom_kill counter of memory.events (cgroup v2) or memory.oom_control (cgroup v1) whose eventfd
+// notifications wake up the detection, the killed process is found in /dev/kmsg which is also used alone when no counter is available
+pub fn start(cgroup_manager: Arc<CgroupManager>, hostname: String) -> Receiver<Event> {
+    let (sender, receiver) = channel();
+    let kmsg = open_kmsg();
+    let mut monitor = OomMonitor {
+        cgroup_manager,
+        hostname,
+        sender,
+        kmsg,
+        killed_processes: Vec::new(),
+        jobs: HashMap::new(),
+    };
+    let _child = thread::spawn(move || loop {
+        monitor.update_jobs();
+        let notified = monitor.wait_notifications();
+        monitor.read_kmsg();
+        monitor.check_jobs(notified);
+    });
+    receiver
+}
+
+fn open_kmsg() -> Option<File> {
+    match OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open("/dev/kmsg") {
+        Ok(mut file) => {
+            // only new messages are interesting
+            if let Err(e) = file.seek(SeekFrom::End(0)) {
+                warn!("cannot seek /dev/kmsg: {}", e);
+            }
+            Some(file)
+        }
+        Err(e) => {
+            warn!("cannot open /dev/kmsg, killed processes will not be identified: {}", e);
+            None
+        }
+    }
+}
+
+impl OomMonitor {
+    // follow the jobs of the cgroup manager
+    fn update_jobs(&mut self) {
+        let cgroups = self.cgroup_manager.get_cgroups();
+        self.jobs.retain(|job_id, _| cgroups.contains_key(job_id));
+        for (job_id, cgroup_name) in cgroups {
+            if self.jobs.contains_key(&job_id) {
+                continue;
+            }
+            let eventfd = if self.cgroup_manager.cgroup_v2 {
+                None
+            } else {
+                register_oom_eventfd(&self.cgroup_manager.get_cgroup_path("memory", &cgroup_name))
+            };
+            let oom_kill = read_oom_kill_count(&self.cgroup_manager, &cgroup_name);
+            self.jobs.insert(job_id, JobOomState { cgroup_name, oom_kill, eventfd });
+        }
+    }
+
+    // wait at most CHECK_PERIOD for an oom notification, returns the number of notifications of each notified job
+    fn wait_notifications(&self) -> HashMap<i32, u64> {
+        let mut notified = HashMap::new();
+        let mut job_ids: Vec<i32> = Vec::new();
+        let mut fds: Vec<libc::pollfd> = Vec::new();
+        for (job_id, job) in &self.jobs {
+            if let Some((efd, _)) = job.eventfd {
+                job_ids.push(*job_id);
+                fds.push(libc::pollfd { fd: efd, events: libc::POLLIN, revents: 0 });
+            }
+        }
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, CHECK_PERIOD) };
+        if res <= 0 {
+            return notified;
+        }
+        for (job_id, fd) in job_ids.iter().zip(fds.iter()) {
+            if fd.revents & libc::POLLIN != 0 {
+                // the eventfd counter is the number of notifications since the last read, and is reset by the read
+                let mut count: u64 = 0;
+                let len = unsafe { libc::read(fd.fd, &mut count as *mut u64 as *mut libc::c_void, 8) };
+                if len == 8 && count > 0 {
+                    notified.insert(*job_id, count);
+                }
+            }
+        }
+        notified
+    }
+
+    // read the new records of /dev/kmsg and keep the ones about killed processes
+    fn read_kmsg(&mut self) {
+        let kmsg = match self.kmsg.as_mut() {
+            Some(kmsg) => kmsg,
+            None => return,
+        };
+        // each read returns one record "priority,sequence,timestamp,flags;message"
+        let mut buffer = [0u8; 8192];
+        loop {
+            let len = match kmsg.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue, // records were overwritten
+                Err(e) => {
+                    debug!("cannot read /dev/kmsg: {}", e);
+                    break;
+                }
+            };
+            parse_kmsg_record(&mut self.killed_processes, &String::from_utf8_lossy(&buffer[..len]));
+        }
+    }
+
+    // send an event for each new oom kill of each job
+    fn check_jobs(&mut self, notified: HashMap<i32, u64>) {
+        let mut killed_processes = std::mem::take(&mut self.killed_processes);
+        let mut events: Vec<Event> = Vec::new();
+        for (job_id, job) in self.jobs.iter_mut() {
+            let oom_kill = read_oom_kill_count(&self.cgroup_manager, &job.cgroup_name);
+            let mut job_processes: Vec<KilledProcess> = Vec::new();
+            killed_processes.retain(|p| {
+                let in_job = p.memcg.split('/').any(|c| c == job.cgroup_name);
+                if in_job {
+                    job_processes.push(p.clone());
+                }
+                !in_job
+            });
+            // counters are authoritative, records of /dev/kmsg only name the killed processes
+            let nb_kills = match (oom_kill, job.oom_kill) {
+                (Some(current), Some(previous)) => (current - previous).max(0) as usize,
+                (Some(_), None) => 0,
+                (None, _) if job.eventfd.is_some() => notified.get(job_id).copied().unwrap_or(0) as usize,
+                (None, _) => job_processes.len(),
+            };
+            job.oom_kill = oom_kill;
+            // records without memory cgroup (kernel < 4.19) are given to the jobs whose counter increased
+            while job_processes.len() < nb_kills {
+                match killed_processes.iter().position(|p| p.memcg.is_empty()) {
+                    Some(i) => job_processes.push(killed_processes.remove(i)),
+                    None => break,
+                }
+            }
+            for i in 0..nb_kills {
+                let event = make_event(&self.cgroup_manager, &self.hostname, *job_id, &job.cgroup_name, job_processes.get(i));
+                warn!("oom kill in job {}: {:?}", job_id, event.details);
+                events.push(event);
+            }
+        }
+        // remaining records are about processes outside of the jobs
+        if !killed_processes.is_empty() {
+            debug!("oom kills outside of jobs: {:?}", killed_processes);
+        }
+        for event in events {
+            if self.sender.send(event).is_err() {
+                debug!("oom events receiver closed");
+            }
+        }
+    }
+}
+
+fn make_event(cgroup_manager: &CgroupManager, hostname: &str, job_id: i32, cgroup_name: &str, process: Option<&KilledProcess>) -> Event {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
+    let mut details = read_memory_state(cgroup_manager, cgroup_name);
+    details.insert("cgroup".to_string(), cgroup_name.to_string());
+    match process {
+        Some(p) => {
+            details.insert("pid".to_string(), p.pid.clone());
+            details.insert("process".to_string(), p.name.clone());
+            if !p.anon_rss.is_empty() {
+                details.insert("anon_rss_kb".to_string(), p.anon_rss.clone());
+                details.insert("file_rss_kb".to_string(), p.file_rss.clone());
+            }
+        }
+        None => {
+            details.insert("process".to_string(), "unknown".to_string());
+        }
+    }
+    Event {
+        event_type: "oom_kill".to_string(),
+        hostname: hostname.to_string(),
+        timestamp,
+        job_id,
+        details,
+    }
+}
+
+// update the killed processes with a record of /dev/kmsg, the message may be followed by continuation lines
+fn parse_kmsg_record(killed_processes: &mut Vec<KilledProcess>, record: &str) {
+    let message = match record.split_once(';') {
+        Some((_, message)) => message.lines().next().unwrap_or(""),
+        None => return,
+    };
+    if let Some(c) = REGEX_OOM_KILL.captures(message) {
+        let process = get_killed_process(killed_processes, &c[3]);
+        process.memcg = c[1].to_string();
+        process.name = c[2].to_string();
+    } else if let Some(c) = REGEX_KILLED_PROCESS.captures(message) {
+        let process = get_killed_process(killed_processes, &c[1]);
+        process.name = c[2].to_string();
+        process.anon_rss = c[3].to_string();
+        process.file_rss = c[4].to_string();
+    }
+}
+
+// record of the killed process with given pid, created if it does not exist yet
+fn get_killed_process<'a>(killed_processes: &'a mut Vec<KilledProcess>, pid: &str) -> &'a mut KilledProcess {
+    match killed_processes.iter().position(|p| p.pid == pid) {
+        Some(i) => &mut killed_processes[i],
+        None => {
+            killed_processes.push(KilledProcess { pid: pid.to_string(), ..Default::default() });
+            killed_processes.last_mut().unwrap()
+        }
+    }
+}
+
+// cgroup v1 oom notification: write "<eventfd> <fd of memory.oom_control>" to cgroup.event_control
+fn register_oom_eventfd(memory_path: &str) -> Option<(RawFd, File)> {
+    let oom_control = File::open(format!("{}/memory.oom_control", memory_path)).ok()?;
+    let efd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if efd < 0 {
+        return None;
+    }
+    let registered = OpenOptions::new()
+        .write(true)
+        .open(format!("{}/cgroup.event_control", memory_path))
+        .and_then(|mut f| f.write_all(format!("{} {}", efd, oom_control.as_raw_fd()).as_bytes()));
+    match registered {
+        Ok(_) => Some((efd, oom_control)),
+        Err(e) => {
+            debug!("cannot register oom notification for {}: {}", memory_path, e);
+            unsafe { libc::close(efd) };
+            None
+        }
+    }
+}
+
+// oom_kill line of memory.events (cgroup v2) or memory.oom_control (cgroup v1, kernel >= 4.13)
+fn read_oom_kill_count(cgroup_manager: &CgroupManager, cgroup_name: &str) -> Option<i64> {
+    let memory_path = cgroup_manager.get_cgroup_path("memory", cgroup_name);
+    let filename = if cgroup_manager.cgroup_v2 {
+        format!("{}/memory.events", memory_path)
+    } else {
+        format!("{}/memory.oom_control", memory_path)
+    };
+    let content = fs::read_to_string(filename).ok()?;
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() == 2 && fields[0] == "oom_kill" {
+            return fields[1].parse::<i64>().ok();
+        }
+    }
+    None
+}
+
+// memory usage and limit of the job, in bytes
+fn read_memory_state(cgroup_manager: &CgroupManager, cgroup_name: &str) -> HashMap<String, String> {
+    let memory_path = cgroup_manager.get_cgroup_path("memory", cgroup_name);
+    let files: [(&str, &str); 3] = if cgroup_manager.cgroup_v2 {
+        [("memory_usage", "memory.current"), ("memory_max_usage", "memory.peak"), ("memory_limit", "memory.max")]
+    } else {
+        [("memory_usage", "memory.usage_in_bytes"), ("memory_max_usage", "memory.max_usage_in_bytes"), ("memory_limit", "memory.limit_in_bytes")]
+    };
+    let mut state = HashMap::new();
+    for (name, file) in files.iter() {
+        if let Ok(content) = fs::read_to_string(format!("{}/{}", memory_path, file)) {
+            state.insert(name.to_string(), content.trim().to_string());
+        }
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(records: &[&str]) -> Vec<KilledProcess> {
+        let mut killed_processes = Vec::new();
+        for record in records {
+            parse_kmsg_record(&mut killed_processes, record);
+        }
+        killed_processes
+    }
+
+    #[test]
+    fn test_parse_kmsg_cgroup_v1() {
+        let processes = parse(&[
+            "6,3562,88371933012,-;stress invoked oom-killer: gfp_mask=0xcc0(GFP_KERNEL), order=0, oom_score_adj=0",
+            "6,3601,88371933240,-;oom-kill:constraint=CONSTRAINT_MEMCG,nodemask=(null),cpuset=user_42,mems_allowed=0-1,\
+oom_memcg=/oar/user_42,task_memcg=/oar/user_42,task=stress,pid=12345,uid=1000",
+            "3,3602,88371933262,-;Memory cgroup out of memory: Killed process 12345 (stress) total-vm:1056784kB, \
+anon-rss:1046616kB, file-rss:1072kB, shmem-rss:0kB, UID:1000 pgtables:2104kB oom_score_adj:0",
+        ]);
+        assert_eq!(processes.len(), 1);
+        let p = &processes[0];
+        assert_eq!((p.pid.as_str(), p.name.as_str(), p.memcg.as_str()), ("12345", "stress", "/oar/user_42"));
+        assert_eq!((p.anon_rss.as_str(), p.file_rss.as_str()), ("1046616", "1072"));
+    }
+
+    #[test]
+    fn test_parse_kmsg_cgroup_v2() {
+        let processes = parse(&[
+            "6,1205,5123456789,-;oom-kill:constraint=CONSTRAINT_MEMCG,nodemask=(null),cpuset=/,mems_allowed=0,\
+oom_memcg=/oar.slice/oar-u42.slice,task_memcg=/oar.slice/oar-u42.slice,task=python3,pid=4242,uid=1000\n SUBSYSTEM=memory",
+            "3,1206,5123456801,-;Memory cgroup out of memory: Killed process 4242 (python3) total-vm:2203480kB, \
+anon-rss:2094136kB, file-rss:5712kB, shmem-rss:0kB, UID:1000 pgtables:4188kB oom_score_adj:0",
+        ]);
+        assert_eq!(processes.len(), 1);
+        let p = &processes[0];
+        assert_eq!((p.pid.as_str(), p.name.as_str(), p.memcg.as_str()), ("4242", "python3", "/oar.slice/oar-u42.slice"));
+        assert_eq!((p.anon_rss.as_str(), p.file_rss.as_str()), ("2094136", "5712"));
+    }
+
+    #[test]
+    fn test_parse_kmsg_without_memcg() {
+        // kernel < 4.19 only reports the killed process
+        let processes = parse(&[
+            "3,812,1203456789,-;Memory cgroup out of memory: Kill process 999 (a.out) score 1000 or sacrifice child",
+            "3,813,1203456790,-;Killed process 999 (a.out) total-vm:4204kB, anon-rss:1024kB, file-rss:88kB, shmem-rss:0kB",
+            "not a record",
+        ]);
+        assert_eq!(processes.len(), 1);
+        let p = &processes[0];
+        assert_eq!((p.pid.as_str(), p.name.as_str(), p.memcg.as_str(), p.anon_rss.as_str()), ("999", "a.out", "", "1024"));
+    }
+}
@@ -2,6 +2,7 @@ use std::path::Path;
 use std::path::PathBuf;
 
 use inotify::{EventMask, Inotify, WatchMask};
+use regex::Regex;
 
 pub fn wait_file(filename: &String, wait: bool) {
     if !Path::new(filename).exists() {
@@ -81,3 +82,81 @@ pub fn round_sampling(default_period: i64, met_period:f32) -> f32{
     }
     (res as f32)/1000.
 }
+
+// include / exclude regular expressions on names (network interfaces, block devices...), an empty pattern selects everything
+pub struct NameFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+}
+
+impl NameFilter {
+    pub fn new(include: &str, exclude: &str) -> NameFilter {
+        let include = compile_pattern(include);
+        let exclude = compile_pattern(exclude);
+        NameFilter { include, exclude }
+    }
+
+    pub fn is_selected(&self, name: &str) -> bool {
+        let included = match &self.include {
+            Some(r) => r.is_match(name),
+            None => true,
+        };
+        let excluded = match &self.exclude {
+            Some(r) => r.is_match(name),
+            None => false,
+        };
+        included && !excluded
+    }
+}
+
+fn compile_pattern(pattern: &str) -> Option<Regex> {
+    if pattern.is_empty() {
+        None
+    } else {
+        Some(Regex::new(pattern).unwrap_or_else(|e| panic!("invalid regular expression {}: {}", pattern, e)))
+    }
+}
+
+// parse a cpu list as found in cpuset.cpus or /sys/devices/system/cpu/online (ex: "0-3,8,10-11")
+pub fn parse_cpu_list(cpu_list: &str) -> Vec<u32> {
+    let mut cpus = Vec::new();
+    for range in cpu_list.trim().split(',').filter(|r| !r.is_empty()) {
+        let bounds: Vec<&str> = range.split('-').collect();
+        let first = bounds[0].parse::<u32>();
+        let last = bounds[bounds.len() - 1].parse::<u32>();
+        if let (Ok(first), Ok(last)) = (first, last) {
+            cpus.extend(first..=last);
+        } else {
+            debug!("invalid cpu range {}", range);
+        }
+    }
+    cpus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_filter() {
+        let all = NameFilter::new("", "");
+        assert!(all.is_selected("eth0") && all.is_selected(""));
+        let include = NameFilter::new("^(eth|ib)[0-9]+$", "");
+        assert!(include.is_selected("eth0") && include.is_selected("ib1"));
+        assert!(!include.is_selected("lo") && !include.is_selected("eth0.100"));
+        let exclude = NameFilter::new("", "^(lo|loop[0-9]+)$");
+        assert!(exclude.is_selected("sda") && !exclude.is_selected("lo") && !exclude.is_selected("loop3"));
+        let both = NameFilter::new("^sd", "^sdb$");
+        assert!(both.is_selected("sda") && !both.is_selected("sdb") && !both.is_selected("nvme0n1"));
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list("5"), vec![5]);
+        assert_eq!(parse_cpu_list(""), Vec::<u32>::new());
+        assert_eq!(parse_cpu_list("\n"), Vec::<u32>::new());
+        // invalid ranges are skipped
+        assert_eq!(parse_cpu_list("0-1,x,4-y,6"), vec![0, 1, 6]);
+    }
+}
@@ -9,9 +9,13 @@ extern crate rustc_serialize;
 use serde::{Deserialize, Serialize};
 use rmps::{Deserializer, Serializer};
 use crate::backends::metric::MetricValues;
+use crate::event::Event;
 
 pub struct ZmqSender {
     sender: zmq::Socket, // sends counters to colmet-collector
+    events_sender: zmq::Socket, // sends events, on their own socket so that collectors that only decode metrics are not affected
+    events_enabled: bool,
+    events_monitor: zmq::Socket, // receives the connections of events_sender, colmet-collector may have restarted
     receiver: zmq::Socket, // receives user configuration
     //backends: &'a Vec<Box<dyn Backend>>,
 }
@@ -21,14 +25,25 @@ impl ZmqSender {
     pub fn init() -> ZmqSender {
         let context = zmq::Context::new();
         let sender = context.socket(zmq::PUSH).unwrap();
+        let events_sender = context.socket(zmq::PUSH).unwrap();
+        events_sender.monitor("inproc://events-monitor", zmq::SocketEvent::CONNECTED as i32).unwrap();
+        let events_monitor = context.socket(zmq::PAIR).unwrap();
+        events_monitor.connect("inproc://events-monitor").unwrap();
         let receiver = context.socket(zmq::PULL).unwrap();
-        ZmqSender{sender, receiver}//, backends}
+        ZmqSender{sender, events_sender, events_enabled: false, events_monitor, receiver}//, backends}
     }
 
-    pub fn open(&self, uri:&str, linger:i32, high_watermark:i32){
+    // events are not sent if events_uri is empty
+    pub fn open(&mut self, uri:&str, events_uri:&str, linger:i32, high_watermark:i32){
         self.sender.connect(uri).unwrap();
         self.sender.set_linger(linger).unwrap();
         self.sender.set_sndhwm(high_watermark).unwrap();
+        if !events_uri.is_empty() {
+            self.events_sender.connect(events_uri).unwrap();
+            self.events_sender.set_linger(linger).unwrap();
+            self.events_sender.set_sndhwm(high_watermark).unwrap();
+            self.events_enabled = true;
+        }
         self.receiver.bind("tcp://0.0.0.0:5557").unwrap();
         self.receiver.set_linger(linger).unwrap();
         self.receiver.set_rcvhwm(high_watermark).unwrap();
@@ -46,6 +61,37 @@ impl ZmqSender {
         self.sender.send(buf, 0).unwrap();
     }
 
+    // an event is an array [event_type, hostname, timestamp, job_id, details]
+    // the event is dropped if it cannot be sent at once (collector down, high water mark reached), the metrics must not wait for it
+    pub fn send_event(&self, event: Event) {
+        if !self.events_enabled {
+            debug!("no events uri, event not sent: {:?}", event);
+            return;
+        }
+        let mut buf = Vec::new();
+        match event.serialize(&mut Serializer::new(&mut buf)){
+            Err(e) => debug!("{}", e),
+            Ok(_t) => ()
+        }
+        if let Err(e) = self.events_sender.send(buf, zmq::DONTWAIT) {
+            warn!("event {} of job {} dropped: {}", event.event_type, event.job_id, e);
+        }
+    }
+
+    // true if the events socket connected to the events uri since the last call (first connection or reconnection)
+    pub fn events_connected(&self) -> bool {
+        let mut connected = false;
+        // a monitor event is 2 frames, the first one starts with the event as a u16
+        while let Ok(frames) = self.events_monitor.recv_multipart(zmq::DONTWAIT) {
+            if let Some([b0, b1, ..]) = frames.first().map(|f| f.as_slice()) {
+                if zmq::SocketEvent::from_raw(u16::from_ne_bytes([*b0, *b1])) == zmq::SocketEvent::CONNECTED {
+                    connected = true;
+                }
+            }
+        }
+        connected
+    }
+
     // receive message containing a new config for colmet, change sample period and metrics collected by backends (only perfhw at the moment)
     pub fn receive_config(&self) -> Option<HashMap<String,String>> {
         let mut message = zmq::Message::new();